@@ -0,0 +1,21 @@
+use std::{env, fs, path::PathBuf};
+
+/// Chips with a partition layout in `memory/`, as named by their cargo feature.
+const CHIPS: [&str; 2] = ["stm32g071rb", "stm32g0b1re"];
+
+fn main() {
+    let chips: Vec<_> = CHIPS
+        .iter()
+        .filter(|chip| env::var_os(format!("CARGO_FEATURE_{}", chip.to_uppercase())).is_some())
+        .collect();
+    let [chip] = chips.as_slice() else {
+        panic!("Expected exactly one chip feature out of {CHIPS:?}");
+    };
+
+    let out = PathBuf::from(env::var_os("OUT_DIR").expect("OUT_DIR should be set"));
+    fs::copy(format!("memory/{chip}.x"), out.join("memory.x")).expect("Expected to copy memory.x");
+    println!("cargo:rustc-link-search={}", out.display());
+
+    println!("cargo:rustc-link-arg-bins=--nmagic");
+    println!("cargo:rustc-link-arg-bins=-Tlink.x");
+}
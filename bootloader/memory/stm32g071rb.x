@@ -0,0 +1,16 @@
+/* STM32G071RB partitions, they have to match memory/stm32g071rb-bootloader.x of the application. */
+MEMORY
+{
+  FLASH            : ORIGIN = 0x08000000, LENGTH = 8K
+  BOOTLOADER_STATE : ORIGIN = 0x08002000, LENGTH = 2K
+  ACTIVE           : ORIGIN = 0x08002800, LENGTH = 50K
+  DFU              : ORIGIN = 0x0800F000, LENGTH = 52K
+  RAM              : ORIGIN = 0x20000000, LENGTH = 36K
+}
+
+__bootloader_state_start = ORIGIN(BOOTLOADER_STATE) - ORIGIN(FLASH);
+__bootloader_state_end = ORIGIN(BOOTLOADER_STATE) + LENGTH(BOOTLOADER_STATE) - ORIGIN(FLASH);
+__bootloader_active_start = ORIGIN(ACTIVE) - ORIGIN(FLASH);
+__bootloader_active_end = ORIGIN(ACTIVE) + LENGTH(ACTIVE) - ORIGIN(FLASH);
+__bootloader_dfu_start = ORIGIN(DFU) - ORIGIN(FLASH);
+__bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU) - ORIGIN(FLASH);
@@ -0,0 +1,17 @@
+/* STM32G0B1RE partitions, they have to match memory/stm32g0b1re-bootloader.x of the application.
+   The bootloader gets 48K for USB DFU and the signature check. */
+MEMORY
+{
+  FLASH            : ORIGIN = 0x08000000, LENGTH = 48K
+  BOOTLOADER_STATE : ORIGIN = 0x0800C000, LENGTH = 2K
+  ACTIVE           : ORIGIN = 0x0800C800, LENGTH = 222K
+  DFU              : ORIGIN = 0x08044000, LENGTH = 224K
+  RAM              : ORIGIN = 0x20000000, LENGTH = 144K
+}
+
+__bootloader_state_start = ORIGIN(BOOTLOADER_STATE) - ORIGIN(FLASH);
+__bootloader_state_end = ORIGIN(BOOTLOADER_STATE) + LENGTH(BOOTLOADER_STATE) - ORIGIN(FLASH);
+__bootloader_active_start = ORIGIN(ACTIVE) - ORIGIN(FLASH);
+__bootloader_active_end = ORIGIN(ACTIVE) + LENGTH(ACTIVE) - ORIGIN(FLASH);
+__bootloader_dfu_start = ORIGIN(DFU) - ORIGIN(FLASH);
+__bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU) - ORIGIN(FLASH);
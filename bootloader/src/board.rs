@@ -0,0 +1,32 @@
+#[cfg(feature = "usb")]
+use embassy_stm32::peripherals::USB;
+use embassy_stm32::{peripherals::FLASH, Peri};
+
+// Each PCB revision has its own board file, picked through a `board-*` cargo feature, as in the
+// application.
+#[cfg(feature = "board-rev1")]
+mod rev1;
+#[cfg(feature = "board-rev1")]
+pub use rev1::init_board;
+#[cfg(all(feature = "board-rev1", feature = "usb"))]
+pub use rev1::{DfuButton, UsbDm, UsbDp};
+
+#[cfg(not(any(feature = "board-rev1")))]
+compile_error!("select the adapter PCB with one of the `board-*` features");
+
+/// Peripherals the bootloader uses, taken from the pins of the selected PCB.
+pub struct Board {
+    pub flash: Peri<'static, FLASH>,
+    #[cfg(feature = "usb")]
+    pub dfu: DfuPins,
+}
+
+/// Pins of the USB DFU mode.
+#[cfg(feature = "usb")]
+pub struct DfuPins {
+    /// User button, held at reset to stay in USB DFU mode.
+    pub button: Peri<'static, DfuButton>,
+    pub usb: Peri<'static, USB>,
+    pub dp: Peri<'static, UsbDp>,
+    pub dm: Peri<'static, UsbDm>,
+}
@@ -0,0 +1,28 @@
+#[cfg(feature = "usb")]
+use embassy_stm32::peripherals::{PA11, PA12, PC13};
+use embassy_stm32::Peripherals;
+
+use super::Board;
+#[cfg(feature = "usb")]
+use super::DfuPins;
+
+#[cfg(feature = "usb")]
+pub type DfuButton = PC13;
+#[cfg(feature = "usb")]
+pub type UsbDp = PA12;
+#[cfg(feature = "usb")]
+pub type UsbDm = PA11;
+
+/// Takes the pins of the first adapter PCB revision.
+pub const fn init_board(p: Peripherals) -> Board {
+    Board {
+        flash: p.FLASH,
+        #[cfg(feature = "usb")]
+        dfu: DfuPins {
+            button: p.PC13,
+            usb: p.USB,
+            dp: p.PA12,
+            dm: p.PA11,
+        },
+    }
+}
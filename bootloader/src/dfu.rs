@@ -0,0 +1,138 @@
+use embassy_boot_stm32::{AlignedBuffer, BlockingFirmwareUpdater, FirmwareUpdaterConfig, State};
+use embassy_stm32::{
+    bind_interrupts,
+    flash::{FLASH_BASE, WRITE_SIZE},
+    gpio::{Input, Pull},
+    peripherals::{self, USB},
+    rcc::{mux, Hsi48Config},
+    usb::{self, Driver},
+    Config, Peri,
+};
+use embassy_usb::Builder;
+use embassy_usb_dfu::{consts::DfuAttributes, usb_dfu, Control, ResetImmediate};
+
+use crate::{
+    board::{DfuButton, UsbDm, UsbDp},
+    SharedFlash, HEADER_SIZE,
+};
+
+bind_interrupts!(struct Irqs {
+    USB_UCPD1_2 => usb::InterruptHandler<peripherals::USB>;
+});
+
+/// USB IDs of the DFU mode, set at build time through `HOPPER_USB_VID` and `HOPPER_USB_PID` as 4
+/// hex digits. Without them the pid.codes test IDs are used, only fit for the bench.
+const USB_VID: u16 = parse_usb_id(option_env!("HOPPER_USB_VID"), 0x1209);
+const USB_PID: u16 = parse_usb_id(option_env!("HOPPER_USB_PID"), 0x0001);
+/// Bytes written to the update slot per DFU download request, one flash page.
+const BLOCK_SIZE: usize = 2048;
+
+const fn parse_usb_id(value: Option<&str>, default: u16) -> u16 {
+    let Some(value) = value else {
+        return default;
+    };
+
+    let bytes = value.as_bytes();
+    assert!(bytes.len() == 4, "USB IDs must be 4 hex digits");
+    let mut id = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        let digit = match bytes[i] {
+            b'0'..=b'9' => bytes[i] - b'0',
+            b'a'..=b'f' => bytes[i] - b'a' + 10,
+            b'A'..=b'F' => bytes[i] - b'A' + 10,
+            _ => panic!("USB IDs must be 4 hex digits"),
+        };
+        id = id << 4 | digit as u16;
+        i += 1;
+    }
+    id
+}
+
+/// Clocks USB from the HSI48, trimmed from the USB start of frame.
+pub const fn configure_clocks(config: &mut Config) {
+    config.rcc.hsi48 = Some(Hsi48Config {
+        sync_from_usb: true,
+    });
+    config.rcc.mux.usbsel = mux::Usbsel::HSI48;
+}
+
+/// Whether to stay in USB DFU mode: the user button is held at reset, the application asked for
+/// it through handheld function 11 or there is no application to start.
+///
+/// A swapped in image is always started first, the update slot holds the previous image until it
+/// confirms its boot.
+pub fn is_requested(
+    state: &State,
+    flash: &SharedFlash,
+    active_offset: u32,
+    active_size: u32,
+    button: Peri<'static, DfuButton>,
+) -> bool {
+    if *state == State::Swap {
+        return false;
+    }
+    let button = Input::new(button, Pull::Up);
+    // Lets the pull-up charge the button line.
+    cortex_m::asm::delay(1_000);
+    *state == State::DfuDetach
+        || button.is_low()
+        || !has_application(flash, active_offset, active_size)
+}
+
+/// Whether the active partition starts with the vector table of an application linked for it:
+/// an initial stack pointer in SRAM and a reset handler inside the partition.
+fn has_application(flash: &SharedFlash, offset: u32, size: u32) -> bool {
+    let mut vectors = [0; 8];
+    let offset = offset + HEADER_SIZE;
+    if flash
+        .lock(|flash| flash.borrow_mut().blocking_read(offset, &mut vectors))
+        .is_err()
+    {
+        return false;
+    }
+    let stack_pointer = u32::from_le_bytes([vectors[0], vectors[1], vectors[2], vectors[3]]);
+    let reset = u32::from_le_bytes([vectors[4], vectors[5], vectors[6], vectors[7]]);
+
+    #[allow(clippy::cast_possible_truncation)]
+    let start = FLASH_BASE as u32 + offset;
+    (0x2000_0000..0x2010_0000).contains(&stack_pointer)
+        && (start..start + size - HEADER_SIZE).contains(&(reset & !1))
+}
+
+/// Receives an image into the update slot over USB DFU, then resets so it gets swapped in. It
+/// is rolled back like any other update if it fails to confirm its boot.
+pub fn run(
+    flash: &SharedFlash,
+    usb: Peri<'static, USB>,
+    dp: Peri<'static, UsbDp>,
+    dm: Peri<'static, UsbDm>,
+) -> ! {
+    let driver = Driver::new(usb, Irqs, dp, dm);
+    let mut config = embassy_usb::Config::new(USB_VID, USB_PID);
+    config.manufacturer = Some("Inotek");
+    config.product = Some("Universal Hopper Adapter DFU");
+
+    let updater_config = FirmwareUpdaterConfig::from_linkerfile_blocking(flash, flash);
+    let mut aligned = AlignedBuffer([0; WRITE_SIZE]);
+    let updater = BlockingFirmwareUpdater::new(updater_config, &mut aligned.0);
+    let mut control =
+        Control::<_, _, _, BLOCK_SIZE>::new(updater, DfuAttributes::CAN_DOWNLOAD, ResetImmediate);
+
+    let mut config_descriptor = [0; 256];
+    let mut bos_descriptor = [0; 256];
+    // DFU downloads come in the data stage of control requests.
+    let mut control_buffer = [0; BLOCK_SIZE];
+    let mut builder = Builder::new(
+        driver,
+        config,
+        &mut config_descriptor,
+        &mut bos_descriptor,
+        &mut [],
+        &mut control_buffer,
+    );
+    usb_dfu(&mut builder, &mut control, |_| {});
+
+    let mut device = builder.build();
+    embassy_futures::block_on(device.run())
+}
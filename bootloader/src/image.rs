@@ -0,0 +1,80 @@
+use salty::{PublicKey, Sha512, Signature};
+
+use crate::{SharedFlash, HEADER_SIZE};
+
+/// Start of the header written by `tools/sign_image.py`:
+/// `[ magic ] [ body length ] [ SHA-512 of the body ] [ Ed25519 signature of the digest ]`
+const MAGIC: [u8; 4] = *b"UHAI";
+const DIGEST_OFFSET: usize = 8;
+const SIGNATURE_OFFSET: usize = DIGEST_OFFSET + 64;
+/// Flash read while hashing the body.
+const CHUNK_SIZE: usize = 256;
+
+/// Key the images are signed with, set at build time through `HOPPER_PUBLIC_KEY` as 64 hex
+/// digits.
+const PUBLIC_KEY: [u8; 32] = parse_public_key(option_env!("HOPPER_PUBLIC_KEY"));
+
+const fn parse_public_key(value: Option<&str>) -> [u8; 32] {
+    let Some(value) = value else {
+        panic!("signed images need the public key in HOPPER_PUBLIC_KEY");
+    };
+
+    let bytes = value.as_bytes();
+    assert!(
+        bytes.len() == 64,
+        "public key must be 64 hex digits, as printed by tools/sign_image.py"
+    );
+    let mut key = [0; 32];
+    let mut i = 0;
+    while i < bytes.len() {
+        let digit = match bytes[i] {
+            b'0'..=b'9' => bytes[i] - b'0',
+            b'a'..=b'f' => bytes[i] - b'a' + 10,
+            b'A'..=b'F' => bytes[i] - b'A' + 10,
+            _ => panic!("public key must be 64 hex digits, as printed by tools/sign_image.py"),
+        };
+        key[i / 2] = key[i / 2] << 4 | digit;
+        i += 1;
+    }
+    key
+}
+
+/// Whether the active partition holds an image signed with [`PUBLIC_KEY`]: the header digest
+/// matches the body and the signature of the digest checks out.
+pub fn is_signed(flash: &SharedFlash, offset: u32, size: u32) -> bool {
+    flash.lock(|flash| {
+        let mut flash = flash.borrow_mut();
+        let mut header = [0; SIGNATURE_OFFSET + 64];
+        if flash.blocking_read(offset, &mut header).is_err() || header[..4] != MAGIC {
+            return false;
+        }
+        let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        if length > size - HEADER_SIZE {
+            return false;
+        }
+
+        let mut sha512 = Sha512::new();
+        let mut chunk = [0; CHUNK_SIZE];
+        let body = offset + HEADER_SIZE;
+        for start in (body..body + length).step_by(CHUNK_SIZE) {
+            let chunk = &mut chunk[..CHUNK_SIZE.min((body + length - start) as usize)];
+            if flash.blocking_read(start, chunk).is_err() {
+                return false;
+            }
+            sha512.update(chunk);
+        }
+        let digest = sha512.finalize();
+        if digest[..] != header[DIGEST_OFFSET..SIGNATURE_OFFSET] {
+            return false;
+        }
+
+        let Ok(public_key) = PublicKey::try_from(&PUBLIC_KEY) else {
+            return false;
+        };
+        let mut signature = [0; 64];
+        signature.copy_from_slice(&header[SIGNATURE_OFFSET..]);
+        public_key
+            .verify(&digest, &Signature::from(&signature))
+            .is_ok()
+    })
+}
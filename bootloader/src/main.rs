@@ -0,0 +1,97 @@
+#![no_std]
+#![no_main]
+
+use core::cell::RefCell;
+
+use cortex_m_rt::{entry, exception};
+#[cfg(feature = "signed-images")]
+use embassy_boot_stm32::State;
+use embassy_boot_stm32::{BootLoader, BootLoaderConfig};
+use embassy_stm32::flash::{Blocking, Flash, FLASH_BASE, MAX_ERASE_SIZE};
+use embassy_sync::blocking_mutex::{raw::NoopRawMutex, Mutex};
+
+mod board;
+#[cfg(feature = "usb")]
+mod dfu;
+#[cfg(feature = "signed-images")]
+mod image;
+
+#[cfg(all(feature = "signed-images", feature = "stm32g071rb"))]
+compile_error!("the signature check does not fit the bootloader partition of the STM32G071RB");
+
+/// Bytes reserved in front of the application for the image header, the vector table follows it
+/// with the alignment the Cortex-M0+ requires. It has to match `memory/` of the application.
+const HEADER_SIZE: u32 = 256;
+
+/// Flash shared by the partitions of the bootloader.
+type SharedFlash = Mutex<NoopRawMutex, RefCell<Flash<'static, Blocking>>>;
+
+/// Swaps in an image the application marked as updated, or swaps the previous one back when the
+/// new image reset before confirming its boot, then starts the application.
+///
+/// On chips with USB the bootloader stays in USB DFU mode instead while the user button is held
+/// at reset, when the application asked for it or when there is no application to start. With
+/// `signed-images`, an image that is not signed is never started.
+#[entry]
+fn main() -> ! {
+    #[allow(unused_mut)]
+    let mut config = embassy_stm32::Config::default();
+    #[cfg(feature = "usb")]
+    dfu::configure_clocks(&mut config);
+    let board = board::init_board(embassy_stm32::init(config));
+
+    let flash: SharedFlash = Mutex::new(RefCell::new(Flash::new_blocking(board.flash)));
+    let config = BootLoaderConfig::from_linkerfile_blocking(&flash, &flash, &flash);
+    let active_offset = config.active.offset();
+    #[cfg(any(feature = "usb", feature = "signed-images"))]
+    let active_size = config.active.size();
+    let bootloader = BootLoader::prepare::<_, _, _, MAX_ERASE_SIZE>(config);
+
+    #[cfg(feature = "usb")]
+    if dfu::is_requested(
+        &bootloader.state,
+        &flash,
+        active_offset,
+        active_size,
+        board.dfu.button,
+    ) {
+        dfu::run(&flash, board.dfu.usb, board.dfu.dp, board.dfu.dm);
+    }
+
+    #[cfg(feature = "signed-images")]
+    if !image::is_signed(&flash, active_offset, active_size) {
+        // A swapped in image that is not signed is swapped back out on the next start.
+        if bootloader.state == State::Swap {
+            cortex_m::peripheral::SCB::sys_reset();
+        }
+        #[cfg(feature = "usb")]
+        dfu::run(&flash, board.dfu.usb, board.dfu.dp, board.dfu.dm);
+        #[cfg(not(feature = "usb"))]
+        loop {
+            cortex_m::asm::wfi();
+        }
+    }
+
+    // SAFETY: the active partition holds an application linked for it, see `memory/`.
+    #[allow(clippy::cast_possible_truncation)]
+    unsafe {
+        bootloader.load(FLASH_BASE as u32 + active_offset + HEADER_SIZE)
+    }
+}
+
+/// A fault during a swap resets, the swap is resumed on the next start.
+#[unsafe(no_mangle)]
+#[cfg_attr(target_os = "none", link_section = ".HardFault.user")]
+unsafe extern "C" fn HardFault() {
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+#[exception]
+unsafe fn DefaultHandler(_: i16) -> ! {
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    cortex_m::asm::udf();
+}
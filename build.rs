@@ -1,4 +1,35 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+/// Chips with a memory layout in `memory/`, as named by their cargo feature.
+const CHIPS: [&str; 4] = ["stm32g071rb", "stm32g030c8", "stm32g031c8", "stm32g0b1re"];
+
 fn main() {
+    let chips: Vec<_> = CHIPS
+        .iter()
+        .filter(|chip| env::var_os(format!("CARGO_FEATURE_{}", chip.to_uppercase())).is_some())
+        .collect();
+    let [chip] = chips.as_slice() else {
+        panic!("Expected exactly one chip feature out of {CHIPS:?}");
+    };
+
+    // The storage area is kept out of the application through our own memory.x, which also
+    // places it behind the bootloader.
+    let layout = if env::var_os("CARGO_FEATURE_BOOTLOADER").is_some() {
+        format!("memory/{chip}-bootloader.x")
+    } else {
+        format!("memory/{chip}.x")
+    };
+    assert!(
+        Path::new(&layout).exists(),
+        "{chip} has no room for the bootloader and an update slot"
+    );
+    let out = PathBuf::from(env::var_os("OUT_DIR").expect("OUT_DIR should be set"));
+    fs::copy(layout, out.join("memory.x")).expect("Expected to copy memory.x");
+    println!("cargo:rustc-link-search={}", out.display());
+
     println!("cargo:rustc-link-arg-bins=--nmagic");
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
     #[cfg(feature = "defmt")]
@@ -0,0 +1,6 @@
+/* STM32G030C8, the last 16K of flash hold the adapter storage (see src/storage.rs). */
+MEMORY
+{
+  FLASH : ORIGIN = 0x08000000, LENGTH = 64K - 16K
+  RAM   : ORIGIN = 0x20000000, LENGTH = 8K
+}
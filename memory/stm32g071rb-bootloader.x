@@ -0,0 +1,23 @@
+/* STM32G071RB behind the bootloader, the partitions have to match bootloader/memory/. The
+   update slot is one page larger than the application for the swap, the last 16K of flash hold
+   the adapter storage (see src/storage.rs). The application partition starts with the image
+   header filled in by tools/sign_image.py. */
+MEMORY
+{
+  BOOTLOADER       : ORIGIN = 0x08000000, LENGTH = 8K
+  BOOTLOADER_STATE : ORIGIN = 0x08002000, LENGTH = 2K
+  IMAGE_HEADER     : ORIGIN = 0x08002800, LENGTH = 256
+  FLASH            : ORIGIN = 0x08002900, LENGTH = 50K - 256
+  DFU              : ORIGIN = 0x0800F000, LENGTH = 52K
+  RAM              : ORIGIN = 0x20000000, LENGTH = 36K
+}
+
+__bootloader_state_start = ORIGIN(BOOTLOADER_STATE) - ORIGIN(BOOTLOADER);
+__bootloader_state_end = ORIGIN(BOOTLOADER_STATE) + LENGTH(BOOTLOADER_STATE) - ORIGIN(BOOTLOADER);
+__bootloader_dfu_start = ORIGIN(DFU) - ORIGIN(BOOTLOADER);
+__bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU) - ORIGIN(BOOTLOADER);
+
+SECTIONS
+{
+  .image_header : { KEEP(*(.image_header)) } > IMAGE_HEADER
+} INSERT BEFORE .vector_table;
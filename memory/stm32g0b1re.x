@@ -0,0 +1,6 @@
+/* STM32G0B1RE, the last 16K of flash hold the adapter storage (see src/storage.rs). */
+MEMORY
+{
+  FLASH : ORIGIN = 0x08000000, LENGTH = 512K - 16K
+  RAM   : ORIGIN = 0x20000000, LENGTH = 144K
+}
@@ -0,0 +1,258 @@
+use core::cell::RefCell;
+
+use defmt::{error, info, warn};
+use embassy_futures::select::{select, Either};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use embassy_time::{Duration, Timer};
+
+use crate::{
+    events::{log_event, Event},
+    payout::is_motor_running,
+    storage::{Journal, RECORD_OVERHEAD},
+    watchdog::{last_reset_cause, supervised, ResetCause, SupervisedTask},
+};
+
+/// Audit meters kept across power cycles, read and cleared over ccTalk through handheld functions
+/// 4 and 5. The adapter has no service console to read them from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum AuditCounter {
+    CoinsPaid,
+    CoinsUnpaid,
+    /// Payouts that started the motor.
+    Payouts,
+    EmergencyStops,
+    /// Exit opto blockages during a payout that were cleared.
+    JamsCleared,
+    SecurityTrips,
+    HopperResets,
+    /// Controller resets other than power-ups: watchdog, software, reset pin.
+    ControllerResets,
+    PowerCycles,
+    MotorRuntimeSeconds,
+    Refills,
+    CoinsRefilled,
+}
+
+impl AuditCounter {
+    pub const ALL: [Self; 12] = [
+        Self::CoinsPaid,
+        Self::CoinsUnpaid,
+        Self::Payouts,
+        Self::EmergencyStops,
+        Self::JamsCleared,
+        Self::SecurityTrips,
+        Self::HopperResets,
+        Self::ControllerResets,
+        Self::PowerCycles,
+        Self::MotorRuntimeSeconds,
+        Self::Refills,
+        Self::CoinsRefilled,
+    ];
+}
+
+const AUDIT_BYTES: usize = AuditCounter::ALL.len() * 4;
+/// Audit meters use the first two storage pages.
+static AUDIT_JOURNAL: Journal<{ AUDIT_BYTES + RECORD_OVERHEAD }> = Journal::new(0);
+
+/// Key the host has to send along with an audit clear, so meters are not cleared by accident.
+pub const AUDIT_CLEAR_KEY: [u8; 2] = *b"AC";
+/// Time between a save and the save request that made it due, coins still dropping after a motor
+/// stop are then counted and writing flash does not disturb the exit opto timing.
+const AUDIT_SAVE_DELAY: Duration = Duration::from_secs(2);
+/// Save requests, one per motor stop or refill, after which the meters are saved even if the
+/// hopper keeps busy.
+pub(crate) const AUDIT_SAVE_REQUESTS: u32 = 16;
+/// Time without a save request after which changed meters are saved.
+const AUDIT_IDLE_SAVE_DELAY: Duration = Duration::from_secs(15 * 60);
+
+struct Meters {
+    counters: [u32; AuditCounter::ALL.len()],
+    /// Motor runtime not yet accounted for in whole seconds.
+    runtime_remainder: Duration,
+    dirty: bool,
+}
+
+static METERS: Mutex<CriticalSectionRawMutex, RefCell<Meters>> = Mutex::new(RefCell::new(Meters {
+    counters: [0; AuditCounter::ALL.len()],
+    runtime_remainder: Duration::from_ticks(0),
+    dirty: false,
+}));
+static AUDIT_SAVE_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Decides which save requests write the meters.
+///
+/// The audit pages hold 36 records each and the flash is rated for 1000 erase cycles, so the
+/// meters can be saved about 72000 times. Saving once per payout would wear them out on a busy
+/// machine, so requests are coalesced: the meters are saved every [`AUDIT_SAVE_REQUESTS`]
+/// requests, or once the hopper stayed quiet for [`AUDIT_IDLE_SAVE_DELAY`]. That is at most 96
+/// quiet saves a day, the price is that a power loss can cost the meters of the payouts since
+/// the last save.
+pub(crate) struct SavePolicy {
+    pending: u32,
+}
+
+impl SavePolicy {
+    pub(crate) const fn new() -> Self {
+        Self { pending: 0 }
+    }
+
+    /// Counts a save request, returns whether the meters are due to be saved.
+    pub(crate) const fn request(&mut self) -> bool {
+        self.pending += 1;
+        self.pending >= AUDIT_SAVE_REQUESTS
+    }
+
+    /// Starts counting requests over after a save.
+    pub(crate) const fn saved(&mut self) {
+        self.pending = 0;
+    }
+}
+
+/// Adds `amount` to an audit meter.
+pub fn increment(counter: AuditCounter, amount: u32) {
+    if amount == 0 {
+        return;
+    }
+    METERS.lock(|meters| {
+        let mut meters = meters.borrow_mut();
+        let value = &mut meters.counters[counter as usize];
+        *value = value.wrapping_add(amount);
+        meters.dirty = true;
+    });
+}
+
+/// Adds a motor run to the runtime meter.
+pub fn add_motor_runtime(runtime: Duration) {
+    let seconds = METERS.lock(|meters| {
+        let mut meters = meters.borrow_mut();
+        let total = meters.runtime_remainder + runtime;
+        let seconds = total.as_secs();
+        meters.runtime_remainder = total - Duration::from_secs(seconds);
+        seconds
+    });
+    increment(
+        AuditCounter::MotorRuntimeSeconds,
+        u32::try_from(seconds).unwrap_or(u32::MAX),
+    );
+}
+
+/// Returns the current value of every audit meter, in [`AuditCounter::ALL`] order.
+pub fn audit_counters() -> [u32; AuditCounter::ALL.len()] {
+    METERS.lock(|meters| meters.borrow().counters)
+}
+
+/// Tells the audit task the meters changed, [`SavePolicy`] decides when they are saved.
+pub fn request_audit_save() {
+    AUDIT_SAVE_SIGNAL.signal(());
+}
+
+/// Clears every audit meter, returns `false` if `key` is not [`AUDIT_CLEAR_KEY`].
+pub async fn clear_audit_counters(key: [u8; 2]) -> bool {
+    if key != AUDIT_CLEAR_KEY {
+        warn!("audit clear refused, wrong key");
+        return false;
+    }
+
+    METERS.lock(|meters| {
+        let mut meters = meters.borrow_mut();
+        meters.counters = [0; AuditCounter::ALL.len()];
+        meters.dirty = true;
+    });
+    log_event(Event::AuditCleared);
+    save().await;
+    true
+}
+
+/// Saves the meters right away, before a controller reset.
+pub async fn flush_audit_counters() {
+    save().await;
+}
+
+async fn save() {
+    let (counters, dirty) = METERS.lock(|meters| {
+        let mut meters = meters.borrow_mut();
+        let dirty = meters.dirty;
+        meters.dirty = false;
+        (meters.counters, dirty)
+    });
+    if !dirty {
+        return;
+    }
+
+    let mut payload = [0u8; AUDIT_BYTES];
+    for (bytes, value) in payload.chunks_exact_mut(4).zip(counters) {
+        bytes.copy_from_slice(&value.to_le_bytes());
+    }
+    if let Err(error) = AUDIT_JOURNAL.save(&payload).await {
+        error!("failed to save audit counters: {}", error);
+        METERS.lock(|meters| meters.borrow_mut().dirty = true);
+    }
+}
+
+/// Loads the stored meters, adding what was counted since startup.
+async fn load() {
+    let mut payload = [0u8; AUDIT_BYTES];
+    match AUDIT_JOURNAL.load(&mut payload).await {
+        Ok(true) => {}
+        Ok(false) => {
+            info!("no audit counters stored, starting from zero");
+            return;
+        }
+        Err(error) => {
+            error!("failed to load audit counters: {}", error);
+            return;
+        }
+    }
+
+    METERS.lock(|meters| {
+        let mut meters = meters.borrow_mut();
+        for (value, bytes) in meters.counters.iter_mut().zip(payload.chunks_exact(4)) {
+            let stored = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            *value = value.wrapping_add(stored);
+        }
+    });
+    info!("audit counters loaded: {}", audit_counters());
+}
+
+/// Loads the audit meters, counts the reset that started the controller and saves the meters
+/// when [`SavePolicy`] has them due and the motor is off.
+#[embassy_executor::task]
+pub async fn audit_task() {
+    info!("audit task started");
+    load().await;
+
+    if last_reset_cause() == ResetCause::BrownOut {
+        increment(AuditCounter::PowerCycles, 1);
+    } else {
+        increment(AuditCounter::ControllerResets, 1);
+    }
+    save().await;
+
+    let mut policy = SavePolicy::new();
+    loop {
+        let event = supervised(
+            SupervisedTask::Audit,
+            select(
+                AUDIT_SAVE_SIGNAL.wait(),
+                Timer::after(AUDIT_IDLE_SAVE_DELAY),
+            ),
+        )
+        .await;
+        if matches!(event, Either::First(())) {
+            if !policy.request() {
+                continue;
+            }
+            supervised(SupervisedTask::Audit, Timer::after(AUDIT_SAVE_DELAY)).await;
+        }
+
+        if is_motor_running().await {
+            // Saved on the next request or once the hopper is quiet.
+            continue;
+        }
+        save().await;
+        policy.saved();
+    }
+}
@@ -0,0 +1,53 @@
+use embassy_stm32::{
+    exti::ExtiInput,
+    gpio::{Input, Output},
+    peripherals::{FLASH, IWDG, RTC},
+    usart::{Config as UartConfig, DataBits, Parity, StopBits},
+    Peri,
+};
+
+// Each PCB revision has its own board file, picked through a `board-*` cargo feature.
+#[cfg(feature = "board-rev1")]
+mod rev1;
+#[cfg(feature = "board-rev1")]
+pub use rev1::{init_board, CcTalkBus};
+
+#[cfg(not(any(feature = "board-rev1")))]
+compile_error!("select the adapter PCB with one of the `board-*` features");
+
+/// Pins and peripherals the firmware drives, set up for the selected PCB.
+///
+/// A new board is supported by adding a board file with an `init_board` building this from the
+/// chip peripherals, and a `CcTalkBus` handing out the ccTalk UART.
+pub struct Board {
+    /// Hopper reset inputs, idle high.
+    pub in_1: Output<'static>,
+    pub in_2: Output<'static>,
+    /// Hopper motor input, set up with the motor off.
+    pub in_3: Output<'static>,
+    pub exit_sensor: ExtiInput<'static>,
+    pub low_level_sensor: ExtiInput<'static>,
+    pub high_level_sensor: ExtiInput<'static>,
+    pub security_output: ExtiInput<'static>,
+    pub user_button: ExtiInput<'static>,
+    pub status_led: Output<'static>,
+    /// Only fitted on some boards.
+    pub comms_led: Option<Output<'static>>,
+    /// Bus address DIP switches, least significant first.
+    pub address_switches: [Input<'static>; 3],
+    /// Half-duplex ccTalk bus.
+    pub cctalk: CcTalkBus,
+    pub flash: Peri<'static, FLASH>,
+    pub iwdg: Peri<'static, IWDG>,
+    pub rtc: Peri<'static, RTC>,
+}
+
+/// ccTalk line settings, 9600 baud 8N1.
+fn cctalk_uart_config() -> UartConfig {
+    let mut config = UartConfig::default();
+    config.baudrate = 9600;
+    config.data_bits = DataBits::DataBits8;
+    config.stop_bits = StopBits::STOP1;
+    config.parity = Parity::ParityNone;
+    config
+}
@@ -0,0 +1,141 @@
+use embassy_stm32::{
+    bind_interrupts,
+    exti::ExtiInput,
+    gpio::{AfType, Flex, Input, Level, Output, Pull, Speed},
+    interrupt,
+    mode::Async,
+    pac::{self, usart::vals::Wus},
+    peripherals::{self, DMA1_CH1, DMA1_CH2, PB6, USART1},
+    usart::{self, Uart},
+    Peri, Peripherals,
+};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+
+use super::{cctalk_uart_config, Board};
+use crate::profile::HOPPER_PROFILE;
+
+bind_interrupts!(struct Irqs {
+    USART1 => usart::InterruptHandler<peripherals::USART1>, BusWakeHandler;
+});
+
+/// EXTI line of the USART1 wakeup from STOP mode.
+const USART1_WAKEUP_LINE: usize = 25;
+/// Alternate function of USART1 on PB6.
+const USART1_AF: u8 = 0;
+
+static BUS_WAKE_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Ends [`CcTalkBus::wait_for_activity`] on the first start bit seen by the released UART.
+struct BusWakeHandler;
+
+impl interrupt::typelevel::Handler<interrupt::typelevel::USART1> for BusWakeHandler {
+    unsafe fn on_interrupt() {
+        let usart = pac::USART1;
+        let isr = usart.isr().read();
+        // Only the wait sets UESM, the driver handles the interrupt otherwise. A frame starting
+        // while the chip runs may set RXNE rather than WUF, either one ends the wait.
+        if usart.cr1().read().uesm() && (isr.wuf() || isr.rxne()) {
+            usart.cr3().modify(|w| w.set_wufie(false));
+            usart.cr1().modify(|w| w.set_rxneie(false));
+            usart.icr().write(|w| w.set_wuf(true));
+            BUS_WAKE_SIGNAL.signal(());
+        }
+    }
+}
+
+/// ccTalk bus on USART1, half-duplex on PB6.
+pub struct CcTalkBus {
+    usart: Peri<'static, USART1>,
+    pin: Peri<'static, PB6>,
+    tx_dma: Peri<'static, DMA1_CH1>,
+    rx_dma: Peri<'static, DMA1_CH2>,
+}
+
+impl CcTalkBus {
+    /// USART1 is clocked from the HSI16, it wakes the chip from STOP mode on a start bit.
+    pub const CAN_WAKE: bool = true;
+
+    /// Sets up the UART, it can be released again by dropping it.
+    ///
+    /// # Panics
+    ///
+    /// If the UART cannot be configured.
+    pub fn uart(&mut self) -> Uart<'_, Async> {
+        Uart::new_half_duplex(
+            self.usart.reborrow(),
+            self.pin.reborrow(),
+            Irqs,
+            self.tx_dma.reborrow(),
+            self.rx_dma.reborrow(),
+            cctalk_uart_config(),
+            usart::HalfDuplexReadback::NoReadback,
+        )
+        .expect("uart should be configured")
+    }
+
+    /// Waits for the next frame to start while the UART is released.
+    ///
+    /// USART1 is kept enabled behind the back of the driver with only the receiver and the
+    /// wakeup from STOP on a start bit, which does not hold off STOP mode. PB6 shares EXTI
+    /// line 6 with the exit opto on PC6, so the pin itself cannot be used.
+    pub async fn wait_for_activity(&mut self) {
+        let mut pin = Flex::new(self.pin.reborrow());
+        pin.set_as_af_unchecked(USART1_AF, AfType::input(Pull::None));
+
+        let usart = pac::USART1;
+        BUS_WAKE_SIGNAL.reset();
+        pac::RCC.apbenr2().modify(|w| w.set_usart1en(true));
+        // The wakeup source can only be set while the USART is disabled.
+        usart.cr3().write(|w| {
+            w.set_hdsel(true);
+            w.set_wus(Wus::START);
+            w.set_wufie(true);
+        });
+        usart.icr().write(|w| w.set_wuf(true));
+        usart.cr1().write(|w| {
+            w.set_uesm(true);
+            w.set_re(true);
+            w.set_rxneie(true);
+            w.set_ue(true);
+        });
+        pac::EXTI
+            .imr(0)
+            .modify(|w| w.set_line(USART1_WAKEUP_LINE, true));
+
+        BUS_WAKE_SIGNAL.wait().await;
+
+        usart.cr1().write(|w| w.set_ue(false));
+        pac::RCC.apbenr2().modify(|w| w.set_usart1en(false));
+    }
+}
+
+/// Sets up the pins of the first adapter PCB revision.
+#[must_use]
+pub fn init_board(p: Peripherals) -> Board {
+    Board {
+        in_1: Output::new(p.PA0, Level::High, Speed::Low),
+        in_2: Output::new(p.PA1, Level::High, Speed::Low),
+        in_3: Output::new(p.PA4, HOPPER_PROFILE.motor_off_level(), Speed::Low),
+        exit_sensor: ExtiInput::new(p.PC6, p.EXTI6, Pull::Up),
+        low_level_sensor: ExtiInput::new(p.PB2, p.EXTI2, Pull::Up),
+        high_level_sensor: ExtiInput::new(p.PA8, p.EXTI8, Pull::Up),
+        security_output: ExtiInput::new(p.PA9, p.EXTI9, Pull::Up),
+        user_button: ExtiInput::new(p.PC13, p.EXTI13, Pull::Up),
+        status_led: Output::new(p.PA5, Level::Low, Speed::Low),
+        comms_led: None,
+        address_switches: [
+            Input::new(p.PB3, Pull::None),
+            Input::new(p.PB4, Pull::None),
+            Input::new(p.PB5, Pull::None),
+        ],
+        cctalk: CcTalkBus {
+            usart: p.USART1,
+            pin: p.PB6,
+            tx_dma: p.DMA1_CH1,
+            rx_dma: p.DMA1_CH2,
+        },
+        flash: p.FLASH,
+        iwdg: p.IWDG,
+        rtc: p.RTC,
+    }
+}
@@ -1 +1,33 @@
 include!(concat!(env!("OUT_DIR"), "/built.rs"));
+
+/// Build metadata returned by handheld function 6 and logged at startup, as `(name, value)`
+/// pairs indexed from 0. Values the build could not determine read `unknown`.
+///
+/// Only what tells images apart is kept: the build time, toolchain and feature strings would not
+/// fit in the G071 application partition, and a build time would change the image hash on every
+/// build.
+#[must_use]
+pub fn field(index: u8) -> Option<(&'static str, &'static str)> {
+    let field = match index {
+        0 => ("version", PKG_VERSION),
+        1 => ("commit", GIT_COMMIT_HASH.unwrap_or("unknown")),
+        2 => (
+            "dirty",
+            match GIT_DIRTY {
+                Some(true) => "yes",
+                Some(false) => "no",
+                None => "unknown",
+            },
+        ),
+        3 => ("profile", PROFILE),
+        _ => return None,
+    };
+    Some(field)
+}
+
+/// Logs all of the build metadata.
+pub fn log() {
+    for (name, value) in (0..).map_while(field) {
+        defmt::info!("build {}: {}", name, value);
+    }
+}
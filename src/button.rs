@@ -0,0 +1,87 @@
+use defmt::{debug, info, warn};
+use embassy_futures::select::{select, Either};
+use embassy_stm32::exti::ExtiInput;
+use embassy_time::{Duration, Timer};
+
+use crate::{
+    hopper::get_bus_address,
+    led::show_address,
+    payout::{get_payout_status, is_motor_running, is_payout_enabled, request_payout},
+    reset::{send_reset_signal, ResetType},
+};
+
+/// Contact bounce of the button is ignored for this long after each edge.
+const BUTTON_DEBOUNCE: Duration = Duration::from_millis(20);
+/// Presses held for longer are long presses.
+const LONG_PRESS_TIME: Duration = Duration::from_millis(1_500);
+/// Time after a short press in which a second press makes it a double press.
+const DOUBLE_PRESS_WINDOW: Duration = Duration::from_millis(400);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+enum Press {
+    Short,
+    Long,
+    Double,
+}
+
+/// Waits for the button to be released, it is active low.
+async fn wait_for_release(button: &mut ExtiInput<'static>) {
+    button.wait_for_high().await;
+    Timer::after(BUTTON_DEBOUNCE).await;
+}
+
+async fn next_press(button: &mut ExtiInput<'static>) -> Press {
+    button.wait_for_low().await;
+    Timer::after(BUTTON_DEBOUNCE).await;
+
+    match select(wait_for_release(button), Timer::after(LONG_PRESS_TIME)).await {
+        Either::First(()) => {}
+        Either::Second(()) => {
+            wait_for_release(button).await;
+            return Press::Long;
+        }
+    }
+
+    match select(button.wait_for_low(), Timer::after(DOUBLE_PRESS_WINDOW)).await {
+        Either::First(()) => {
+            Timer::after(BUTTON_DEBOUNCE).await;
+            wait_for_release(button).await;
+            Press::Double
+        }
+        Either::Second(()) => Press::Short,
+    }
+}
+
+/// Pays out a single coin, unless payouts are disabled by the host, a payout still owes coins or
+/// the motor is running, so a test never adds to or cuts into a payout of the host.
+async fn test_payout() {
+    if !is_payout_enabled().await {
+        warn!("test payout refused, payouts are disabled");
+    } else if get_payout_status().await.coins_remaining > 0 {
+        warn!("test payout refused, a payout is in progress");
+    } else if is_motor_running().await {
+        warn!("test payout refused, the motor is running");
+    } else {
+        info!("test payout of one coin");
+        request_payout(1);
+    }
+}
+
+/// Service actions on the board's user button, for technicians without a laptop:
+/// - short press: hopper reset,
+/// - long press: single coin test payout, payouts have to be enabled by the host and idle,
+/// - double press: blinks the bus address on the status LED.
+#[embassy_executor::task]
+pub async fn user_button_task(mut button: ExtiInput<'static>) {
+    info!("user button task started");
+    loop {
+        let press = next_press(&mut button).await;
+        debug!("user button press: {}", press);
+
+        match press {
+            Press::Short => send_reset_signal(ResetType::Hopper),
+            Press::Long => test_payout().await,
+            Press::Double => show_address(get_bus_address().await),
+        }
+    }
+}
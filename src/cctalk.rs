@@ -0,0 +1,378 @@
+use cc_talk_core::cc_talk::{
+    deserializer::deserialize, serializer::serialize, Header, Packet, PacketError, SerialCode,
+    MAX_BLOCK_LENGTH,
+};
+use cc_talk_device::{
+    device_impl::{DeviceImpl, SimplePayoutDevice},
+    payout_device::FrameError,
+};
+use core::cell::Cell;
+
+use defmt::{debug, error, warn};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+
+#[cfg(all(feature = "bootloader", feature = "usb"))]
+use crate::firmware::request_usb_dfu;
+#[cfg(feature = "bootloader")]
+use crate::firmware::{begin_upgrade, finish_upgrade, write_line};
+use crate::{
+    audit::{audit_counters, clear_audit_counters, AuditCounter},
+    build_info,
+    config::{payout_config, set_payout_config},
+    events::{log_event, recent_event, Event},
+    faults::fraud_attempts,
+    fill::{fill_estimate, set_fill_estimate},
+    hopper::Hopper,
+    pin::{change_pin, enter_pin, is_unlocked},
+    refill::{begin_refill, end_refill, record_refill},
+    serial::provision_serial,
+    user_data::{read_block, write_block},
+    watchdog::last_reset_cause,
+};
+
+/// The adapter drives a single hopper, multi hopper commands only accept this number.
+const HOPPER_NUMBER: u8 = 1;
+
+/// Handheld function (header 177) recording a refill of a counted number of coins.
+///
+/// Transmitted data: `[ 1 ] [ count LSB ] [ count MSB ]`
+const HANDHELD_COINS_ADDED: u8 = 1;
+/// Handheld function (header 177) starting a refill, payouts are held back until it ends.
+///
+/// Transmitted data: `[ 2 ]`
+const HANDHELD_BEGIN_REFILL: u8 = 2;
+/// Handheld function (header 177) ending a refill, with the number of coins added if they were
+/// counted.
+///
+/// Transmitted data: `[ 3 ]` or `[ 3 ] [ count LSB ] [ count MSB ]`
+const HANDHELD_END_REFILL: u8 = 3;
+/// Handheld function (header 177) reading the audit meters.
+///
+/// Transmitted data: `[ 4 ]`
+/// Received data: one `[ LSB ] .. [ MSB ]` 32-bit value per [`AuditCounter`], in declaration order
+const HANDHELD_READ_AUDIT: u8 = 4;
+/// Handheld function (header 177) clearing the audit meters, refused unless the key matches
+/// [`crate::audit::AUDIT_CLEAR_KEY`].
+///
+/// Transmitted data: `[ 5 ] [ key 1 ] [ key 2 ]`
+const HANDHELD_CLEAR_AUDIT: u8 = 5;
+/// Handheld function (header 177) reading a field of the build metadata, see
+/// [`crate::build_info::field`]. Past the last field the reply is empty.
+///
+/// Transmitted data: `[ 6 ] [ field ]`
+/// Received data: `[ name ] .. [ '=' ] [ value ] ..` in ASCII
+const HANDHELD_READ_BUILD_INFO: u8 = 6;
+/// Handheld function (header 177) programming the serial number at manufacturing, refused once
+/// one was programmed. The serial is LSB first, as in the reply to header 242.
+///
+/// Transmitted data: `[ 7 ] [ serial 1 ] [ serial 2 ] [ serial 3 ]`
+const HANDHELD_PROVISION_SERIAL: u8 = 7;
+/// Handheld function (header 177) reading the number of fraud attempts detected since the
+/// controller started. Payouts are inhibited while it is not zero.
+///
+/// Transmitted data: `[ 8 ]`
+/// Received data: `[ LSB ] .. [ MSB ]` 32-bit count
+const HANDHELD_READ_FRAUD_ATTEMPTS: u8 = 8;
+/// Handheld function (header 177) reading the cause of the last controller reset, e.g. to tell a
+/// watchdog reset from a power cycle.
+///
+/// Transmitted data: `[ 9 ]`
+/// Received data: `[ cause ]`, see [`crate::watchdog::ResetCause`]
+const HANDHELD_READ_RESET_CAUSE: u8 = 9;
+/// Handheld function (header 177) reading the event log, `0` being the latest event. Past the
+/// oldest event the reply is empty.
+///
+/// Transmitted data: `[ 10 ] [ index ]`
+/// Received data: `[ seconds since start LSB ] .. [ MSB ] [ event ] ..`, the event as encoded by
+/// [`Event::encode`]
+const HANDHELD_READ_EVENT: u8 = 10;
+/// Handheld function (header 177) restarting into the USB DFU mode of the bootloader, on chips
+/// with USB. Refused while the motor runs or a new image is on trial.
+///
+/// Transmitted data: `[ 11 ]`
+const HANDHELD_ENTER_USB_DFU: u8 = 11;
+
+/// Handheld functions clearing the meters, programming the serial number or taking the hopper out
+/// of service for USB DFU. The others only read or log a refill, they are answered without the
+/// PIN.
+const PIN_PROTECTED_HANDHELD_FUNCTIONS: [u8; 3] = [
+    HANDHELD_CLEAR_AUDIT,
+    HANDHELD_PROVISION_SERIAL,
+    HANDHELD_ENTER_USB_DFU,
+];
+
+/// Set once another device replied with our address.
+static ADDRESS_CLASH: Mutex<CriticalSectionRawMutex, Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// Whether another device on the bus was seen using our address, until the controller resets.
+pub fn address_clash_detected() -> bool {
+    ADDRESS_CLASH.lock(Cell::get)
+}
+
+/// Checks a frame seen on the bus for another device using our address as its source.
+///
+/// Our own replies are not read back, so a valid frame sent from our address comes from another
+/// device and the host gets garbled replies from both.
+pub fn check_address_clash(frame: &mut [u8]) {
+    let hopper = Hopper;
+    let mut packet = Packet::new(&mut frame[..]);
+    if packet
+        .get_destination()
+        .is_ok_and(|destination| hopper.is_for_me(destination))
+    {
+        return;
+    }
+    let Ok(source) = deserialize(&mut packet, hopper.checksum_type()) else {
+        return;
+    };
+    if source == hopper.address() && !ADDRESS_CLASH.lock(|clash| clash.replace(true)) {
+        warn!("another device replied from bus address {}", source);
+        log_event(Event::AddressClash { address: source });
+    }
+}
+
+/// Whether the command in `packet` goes unanswered while the device is locked.
+fn requires_pin(header: Header, packet: &Packet<&mut [u8]>) -> bool {
+    if header == Header::HandheldFunction {
+        packet.get_data().is_ok_and(|data| {
+            data.first()
+                .is_some_and(|function| PIN_PROTECTED_HANDHELD_FUNCTIONS.contains(function))
+        })
+    } else {
+        Hopper::requires_pin(header)
+    }
+}
+
+/// Whether `header` is handled here rather than by the payout device.
+const fn is_extension(header: Header) -> bool {
+    matches!(
+        header,
+        Header::RequestHopperBalance
+            | Header::ModifyHopperBalance
+            | Header::RequestVariableSet
+            | Header::ModifyVariableSet
+            | Header::HandheldFunction
+            | Header::ReadDataBlock
+            | Header::WriteDataBlock
+            | Header::EnterPinNumber
+            | Header::EnterNewPinNumber
+            | Header::RequestFirmwareUpgradeCapability
+            | Header::BeginFirmwareUpgrade
+            | Header::UploadFirmware
+            | Header::FinishFirmwareUpgrade
+    )
+}
+
+/// Processes the ccTalk commands the payout device does not implement.
+///
+/// Returns `None` when the frame is not one of them, it then has to be passed to the payout
+/// device which also takes care of rejecting invalid frames. A reply length of `0` means the
+/// command must go unanswered, as PIN protected commands do while the device is locked.
+pub async fn on_frame(
+    frame: &mut [u8],
+    reply_buffer: &mut [u8],
+) -> Option<Result<usize, FrameError>> {
+    let hopper = Hopper;
+    let mut packet = Packet::new(&mut frame[..]);
+    if !hopper.is_for_me(packet.get_destination().ok()?) {
+        return None;
+    }
+    let header = packet.get_header().ok()?;
+    if requires_pin(header, &packet) && !is_unlocked().await {
+        warn!("command {} ignored, PIN not entered", header as u8);
+        return Some(Ok(0));
+    }
+    if !is_extension(header) {
+        return None;
+    }
+    let reply_address = deserialize(&mut packet, hopper.checksum_type()).ok()?;
+    debug!("processing adapter command {}", header as u8);
+
+    Some(reply(&hopper, header, &packet, reply_address, reply_buffer).await)
+}
+
+async fn reply(
+    hopper: &Hopper,
+    header: Header,
+    packet: &Packet<&mut [u8]>,
+    reply_address: u8,
+    reply_buffer: &mut [u8],
+) -> Result<usize, FrameError> {
+    let payload = packet.get_data()?;
+    let mut reply_packet = Packet::new(reply_buffer);
+    reply_packet.set_source(hopper.address())?;
+    reply_packet.set_destination(reply_address)?;
+    process_packet(hopper, header, payload, &mut reply_packet).await?;
+
+    match serialize(&hopper.device(), &mut reply_packet) {
+        Ok(()) => Ok(reply_packet.get_logical_size()),
+        Err(error) => {
+            error!("failed to serialize reply packet: {:?}", error);
+            Err(FrameError::SerializationError)
+        }
+    }
+}
+
+/// Field of the build metadata as `name=value`, cut to what fits the frame besides the addresses,
+/// length, header and checksum. Empty past the last field.
+fn build_info_field(index: u8) -> heapless::Vec<u8, { MAX_BLOCK_LENGTH - 5 }> {
+    let mut data = heapless::Vec::new();
+    if let Some((name, value)) = build_info::field(index) {
+        for byte in name.bytes().chain([b'=']).chain(value.bytes()) {
+            if data.push(byte).is_err() {
+                break;
+            }
+        }
+    }
+    data
+}
+
+/// Event log entry `index` as read by the host, empty past the oldest event.
+fn event_record(index: u8) -> heapless::Vec<u8, { 4 + Event::MAX_ENCODED_LEN }> {
+    let mut data = heapless::Vec::new();
+    if let Some(record) = recent_event(usize::from(index)) {
+        let seconds = u32::try_from(record.timestamp.as_secs()).unwrap_or(u32::MAX);
+        // Cannot fail, there is room for the timestamp and any event.
+        let _ = data.extend_from_slice(&seconds.to_le_bytes());
+        let _ = data.extend_from_slice(&record.event.encode());
+    }
+    data
+}
+
+fn nack(packet: &mut Packet<&mut [u8]>, error: impl defmt::Format) -> Result<(), PacketError> {
+    warn!("adapter command refused: {}", error);
+    packet.set_header(Header::NACK)?;
+    packet.set_data(&[])
+}
+
+async fn process_packet(
+    hopper: &Hopper,
+    header: Header,
+    payload: &[u8],
+    packet: &mut Packet<&mut [u8]>,
+) -> Result<(), PacketError> {
+    packet.set_header(Header::Reply)?;
+
+    match (header, payload) {
+        (Header::RequestHopperBalance, [HOPPER_NUMBER]) => {
+            let mut data = [b' '; 8];
+            for (byte, coin) in data[..6]
+                .iter_mut()
+                .zip(hopper.request_hopper_coin().bytes())
+            {
+                *byte = coin;
+            }
+            data[6..].copy_from_slice(&fill_estimate().coins.to_le_bytes());
+            packet.set_data(&data)
+        }
+        (Header::ModifyHopperBalance, &[HOPPER_NUMBER, lsb, msb]) => {
+            set_fill_estimate(u16::from_le_bytes([lsb, msb]));
+            packet.set_data(&[])
+        }
+        (Header::RequestVariableSet, []) => packet.set_data(&payout_config().to_variable_set()),
+        (Header::ModifyVariableSet, data) => match set_payout_config(data).await {
+            Ok(()) => packet.set_data(&[]),
+            Err(error) => nack(packet, error),
+        },
+        (Header::HandheldFunction, function) => process_handheld_function(function, packet).await,
+        (Header::ReadDataBlock, &[block]) => match read_block(block).await {
+            Ok(data) => packet.set_data(&data),
+            Err(error) => nack(packet, error),
+        },
+        (Header::WriteDataBlock, &[block, ref data @ ..]) => match write_block(block, data).await {
+            Ok(()) => packet.set_data(&[]),
+            Err(error) => nack(packet, error),
+        },
+        (Header::EnterPinNumber, &[pin_1, pin_2, pin_3, pin_4]) => {
+            enter_pin(u32::from_le_bytes([pin_1, pin_2, pin_3, pin_4])).await;
+            packet.set_data(&[])
+        }
+        (Header::EnterNewPinNumber, &[pin_1, pin_2, pin_3, pin_4]) => {
+            match change_pin(u32::from_le_bytes([pin_1, pin_2, pin_3, pin_4])).await {
+                Ok(()) => packet.set_data(&[]),
+                Err(error) => nack(packet, error),
+            }
+        }
+        // Firmware in flash, upgradable only when running behind the bootloader.
+        (Header::RequestFirmwareUpgradeCapability, []) => {
+            packet.set_data(&[u8::from(cfg!(feature = "bootloader"))])
+        }
+        #[cfg(feature = "bootloader")]
+        (Header::BeginFirmwareUpgrade, [] | [_]) => match begin_upgrade().await {
+            Ok(()) => packet.set_data(&[]),
+            Err(error) => nack(packet, error),
+        },
+        #[cfg(feature = "bootloader")]
+        (Header::UploadFirmware, &[block, line, ref data @ ..]) => {
+            match write_line(block, line, data).await {
+                Ok(()) => packet.set_data(&[]),
+                Err(error) => nack(packet, error),
+            }
+        }
+        #[cfg(feature = "bootloader")]
+        (Header::FinishFirmwareUpgrade, &[l_1, l_2, l_3, l_4, crc_1, crc_2, crc_3, crc_4]) => {
+            let length = u32::from_le_bytes([l_1, l_2, l_3, l_4]);
+            match finish_upgrade(length, u32::from_le_bytes([crc_1, crc_2, crc_3, crc_4])).await {
+                Ok(()) => packet.set_data(&[]),
+                Err(error) => nack(packet, error),
+            }
+        }
+        _ => {
+            packet.set_header(Header::NACK)?;
+            packet.set_data(&[])
+        }
+    }
+}
+
+async fn process_handheld_function(
+    function: &[u8],
+    packet: &mut Packet<&mut [u8]>,
+) -> Result<(), PacketError> {
+    match function {
+        &[HANDHELD_COINS_ADDED, lsb, msb] => {
+            record_refill(u16::from_le_bytes([lsb, msb]));
+            packet.set_data(&[])
+        }
+        [HANDHELD_BEGIN_REFILL] => {
+            begin_refill();
+            packet.set_data(&[])
+        }
+        [HANDHELD_END_REFILL] => {
+            end_refill(None).await;
+            packet.set_data(&[])
+        }
+        &[HANDHELD_END_REFILL, lsb, msb] => {
+            end_refill(Some(u16::from_le_bytes([lsb, msb]))).await;
+            packet.set_data(&[])
+        }
+        [HANDHELD_READ_AUDIT] => {
+            let mut data = [0u8; AuditCounter::ALL.len() * 4];
+            for (bytes, value) in data.chunks_exact_mut(4).zip(audit_counters()) {
+                bytes.copy_from_slice(&value.to_le_bytes());
+            }
+            packet.set_data(&data)
+        }
+        &[HANDHELD_CLEAR_AUDIT, key_1, key_2] if clear_audit_counters([key_1, key_2]).await => {
+            packet.set_data(&[])
+        }
+        &[HANDHELD_READ_BUILD_INFO, field] => packet.set_data(&build_info_field(field)),
+        &[HANDHELD_PROVISION_SERIAL, serial_1, serial_2, serial_3] => {
+            match provision_serial(SerialCode::new(serial_3, serial_2, serial_1)).await {
+                Ok(()) => packet.set_data(&[]),
+                Err(error) => nack(packet, error),
+            }
+        }
+        [HANDHELD_READ_FRAUD_ATTEMPTS] => packet.set_data(&fraud_attempts().to_le_bytes()),
+        [HANDHELD_READ_RESET_CAUSE] => packet.set_data(&[last_reset_cause() as u8]),
+        &[HANDHELD_READ_EVENT, index] => packet.set_data(&event_record(index)),
+        #[cfg(all(feature = "bootloader", feature = "usb"))]
+        [HANDHELD_ENTER_USB_DFU] => match request_usb_dfu().await {
+            Ok(()) => packet.set_data(&[]),
+            Err(error) => nack(packet, error),
+        },
+        _ => {
+            packet.set_header(Header::NACK)?;
+            packet.set_data(&[])
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use embassy_stm32::{
+    rcc::{
+        mux, AHBPrescaler, APBPrescaler, Hse, HseMode, Hsi, HsiSysDiv, LsConfig, Sysclk,
+        VoltageRange,
+    },
+    time::Hertz,
+    Config,
+};
+
+/// Frequency of the crystal fitted for the system clock, set at build time through
+/// `HOPPER_HSE_HZ`. The internal 16 MHz oscillator is used without one.
+pub const HSE_FREQUENCY: Option<u32> = parse_frequency(option_env!("HOPPER_HSE_HZ"));
+
+const fn parse_frequency(value: Option<&str>) -> Option<u32> {
+    let Some(value) = value else {
+        return None;
+    };
+
+    let bytes = value.as_bytes();
+    assert!(!bytes.is_empty(), "crystal frequency must not be empty");
+    let mut frequency: u32 = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        assert!(
+            bytes[i].is_ascii_digit(),
+            "crystal frequency must be a number"
+        );
+        let Some(shifted) = frequency.checked_mul(10) else {
+            panic!("crystal frequency must be between 4 and 48 MHz");
+        };
+        let Some(next) = shifted.checked_add((bytes[i] - b'0') as u32) else {
+            panic!("crystal frequency must be between 4 and 48 MHz");
+        };
+        frequency = next;
+        i += 1;
+    }
+    assert!(
+        frequency >= 4_000_000 && frequency <= 48_000_000,
+        "crystal frequency must be between 4 and 48 MHz"
+    );
+    Some(frequency)
+}
+
+/// Clock configuration of the adapter.
+///
+/// The system clock runs straight from the HSI16 or the crystal, without the PLL: ccTalk at 9600
+/// baud and the hopper signals need far less, and a slow clock keeps the run current down. The
+/// ccTalk UART is clocked from the HSI16 so its baud rate does not depend on the system clock.
+/// Chips with USB get the HSI48, trimmed from the USB start of frame.
+#[must_use]
+pub fn chip_config() -> Config {
+    let mut config = Config::default();
+    let rcc = &mut config.rcc;
+
+    rcc.hsi = Some(Hsi {
+        sys_div: HsiSysDiv::DIV1,
+    });
+    if let Some(frequency) = HSE_FREQUENCY {
+        rcc.hse = Some(Hse {
+            freq: Hertz(frequency),
+            mode: HseMode::Oscillator,
+        });
+        rcc.sys = Sysclk::HSE;
+    } else {
+        rcc.hse = None;
+        rcc.sys = Sysclk::HSI;
+    }
+    rcc.pll = None;
+    rcc.ahb_pre = AHBPrescaler::DIV1;
+    rcc.apb1_pre = APBPrescaler::DIV1;
+    // Range 2 caps the clocks at 16 MHz, too low for the faster crystals and the HSI48.
+    rcc.voltage_range = VoltageRange::RANGE1;
+    // The LSI clocks the RTC keeping time in STOP mode.
+    rcc.ls = LsConfig::default_lsi();
+    rcc.mux.usart1sel = mux::Usartsel::HSI;
+
+    #[cfg(feature = "usb")]
+    {
+        rcc.hsi48 = Some(embassy_stm32::rcc::Hsi48Config {
+            sync_from_usb: true,
+        });
+        rcc.mux.usbsel = mux::Usbsel::HSI48;
+    }
+
+    // The debug probe loses the chip in STOP mode unless the debug clocks are kept running, at
+    // the cost of the power saved.
+    config.enable_debug_during_sleep = !cfg!(feature = "low-power");
+    config
+}
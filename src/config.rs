@@ -0,0 +1,249 @@
+use core::cell::Cell;
+
+use defmt::{error, info, warn};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Duration;
+
+use crate::{
+    events::{log_event, Event},
+    payout::is_motor_running,
+    profile::HOPPER_PROFILE,
+    storage::{Journal, StorageError, RECORD_OVERHEAD},
+};
+
+/// Payout tuning values settable through the ccTalk variable set (headers 165/247).
+///
+/// The variable set is one `[ LSB ] [ MSB ]` 16-bit value per parameter, in [`Self::ALL`]
+/// order. Times are in milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum ConfigParameter {
+    MinBrakeTime,
+    MinDetectionTime,
+    MaxDetectionTime,
+    MotorMaxTimePerCoin,
+    EmptyNoCoinTimeout,
+    BookKeeperPollInterval,
+    BookKeeperMaxTries,
+    OverpayWindow,
+    /// 1 to deduct overpaid coins from the next payout, 0 to only count them.
+    OverpayCompensation,
+    /// 1 to end a payout early once the hopper is found empty, 0 to leave it to the motor
+    /// interlock and the bookkeeper.
+    EndPayoutWhenEmpty,
+}
+
+impl ConfigParameter {
+    pub const ALL: [Self; 10] = [
+        Self::MinBrakeTime,
+        Self::MinDetectionTime,
+        Self::MaxDetectionTime,
+        Self::MotorMaxTimePerCoin,
+        Self::EmptyNoCoinTimeout,
+        Self::BookKeeperPollInterval,
+        Self::BookKeeperMaxTries,
+        Self::OverpayWindow,
+        Self::OverpayCompensation,
+        Self::EndPayoutWhenEmpty,
+    ];
+
+    /// Safe range of the parameter, bounds included.
+    #[must_use]
+    pub const fn range(self) -> (u16, u16) {
+        match self {
+            Self::MinBrakeTime => (20, 1_000),
+            Self::MinDetectionTime => (5, 100),
+            // The exit sensor task waits this long for a coin to clear between two check ins, it
+            // stays below the 1 s deadline of the task.
+            Self::MaxDetectionTime => (100, 900),
+            Self::MotorMaxTimePerCoin => (100, 5_000),
+            Self::EmptyNoCoinTimeout => (500, 10_000),
+            Self::BookKeeperPollInterval => (1_000, 30_000),
+            Self::BookKeeperMaxTries => (1, 10),
+            Self::OverpayWindow => (0, 5_000),
+            Self::OverpayCompensation | Self::EndPayoutWhenEmpty => (0, 1),
+        }
+    }
+}
+
+/// Size of the variable set exchanged with the host.
+pub const VARIABLE_SET_BYTES: usize = ConfigParameter::ALL.len() * 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum ConfigError {
+    /// The variable set does not hold exactly one value per parameter.
+    InvalidLength,
+    OutOfRange(ConfigParameter),
+    /// The minimum coin detection time is not below the maximum.
+    DetectionTimesInverted,
+    /// Writing flash stalls the CPU, it is not done while the motor runs.
+    Busy,
+    Storage(StorageError),
+}
+
+impl From<StorageError> for ConfigError {
+    fn from(error: StorageError) -> Self {
+        Self::Storage(error)
+    }
+}
+
+/// Timing of the payout, the exit opto and the bookkeeper.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct PayoutConfig {
+    /// Minimum time between stopping the motor and starting it again.
+    pub min_brake_time: Duration,
+    /// Minimum time the exit opto has to be blocked for a pulse to be a coin.
+    pub min_detection_time: Duration,
+    /// Maximum time a coin can block the exit opto, longer pulses mean the path is blocked.
+    pub max_detection_time: Duration,
+    /// Motor run time allowed per coin by the safety interlock, on top of the spin-up allowance.
+    pub motor_max_time_per_coin: Duration,
+    /// Time without coins on the exit opto, with the low level plate uncovered, after which the
+    /// hopper is considered empty.
+    pub empty_no_coin_timeout: Duration,
+    /// Interval at which the bookkeeper checks the payout progress.
+    pub book_keeper_poll_interval: Duration,
+    /// Bookkeeper checks without progress before the remaining coins are marked unpaid.
+    pub book_keeper_max_tries: u8,
+    /// Time the exit opto is watched after the motor stopped for coins dropping through during
+    /// run-down, cut short when the next payout starts.
+    pub overpay_window: Duration,
+    /// Deduct coins dropped during run-down from the next payout.
+    pub overpay_compensation: bool,
+    /// End a payout early, with the remaining coins unpaid, once the hopper is found empty.
+    pub end_payout_when_empty: bool,
+}
+
+impl PayoutConfig {
+    fn value(&self, parameter: ConfigParameter) -> u16 {
+        let time = match parameter {
+            ConfigParameter::MinBrakeTime => self.min_brake_time,
+            ConfigParameter::MinDetectionTime => self.min_detection_time,
+            ConfigParameter::MaxDetectionTime => self.max_detection_time,
+            ConfigParameter::MotorMaxTimePerCoin => self.motor_max_time_per_coin,
+            ConfigParameter::EmptyNoCoinTimeout => self.empty_no_coin_timeout,
+            ConfigParameter::BookKeeperPollInterval => self.book_keeper_poll_interval,
+            ConfigParameter::BookKeeperMaxTries => return u16::from(self.book_keeper_max_tries),
+            ConfigParameter::OverpayWindow => self.overpay_window,
+            ConfigParameter::OverpayCompensation => return u16::from(self.overpay_compensation),
+            ConfigParameter::EndPayoutWhenEmpty => return u16::from(self.end_payout_when_empty),
+        };
+        u16::try_from(time.as_millis()).unwrap_or(u16::MAX)
+    }
+
+    fn set_value(&mut self, parameter: ConfigParameter, value: u16) {
+        let time = Duration::from_millis(u64::from(value));
+        match parameter {
+            ConfigParameter::MinBrakeTime => self.min_brake_time = time,
+            ConfigParameter::MinDetectionTime => self.min_detection_time = time,
+            ConfigParameter::MaxDetectionTime => self.max_detection_time = time,
+            ConfigParameter::MotorMaxTimePerCoin => self.motor_max_time_per_coin = time,
+            ConfigParameter::EmptyNoCoinTimeout => self.empty_no_coin_timeout = time,
+            ConfigParameter::BookKeeperPollInterval => self.book_keeper_poll_interval = time,
+            ConfigParameter::BookKeeperMaxTries => {
+                self.book_keeper_max_tries = u8::try_from(value).unwrap_or(u8::MAX);
+            }
+            ConfigParameter::OverpayWindow => self.overpay_window = time,
+            ConfigParameter::OverpayCompensation => self.overpay_compensation = value != 0,
+            ConfigParameter::EndPayoutWhenEmpty => self.end_payout_when_empty = value != 0,
+        }
+    }
+
+    /// Parses and validates a variable set.
+    ///
+    /// # Errors
+    ///
+    /// If the set has the wrong length or a value is outside of its safe range.
+    pub fn from_variable_set(bytes: &[u8]) -> Result<Self, ConfigError> {
+        if bytes.len() != VARIABLE_SET_BYTES {
+            return Err(ConfigError::InvalidLength);
+        }
+
+        let mut config = HOPPER_PROFILE.payout_config;
+        for (parameter, value) in ConfigParameter::ALL.into_iter().zip(bytes.chunks_exact(2)) {
+            let value = u16::from_le_bytes([value[0], value[1]]);
+            let (min, max) = parameter.range();
+            if !(min..=max).contains(&value) {
+                return Err(ConfigError::OutOfRange(parameter));
+            }
+            config.set_value(parameter, value);
+        }
+        if config.min_detection_time >= config.max_detection_time {
+            return Err(ConfigError::DetectionTimesInverted);
+        }
+        Ok(config)
+    }
+
+    /// Encodes the configuration as a variable set.
+    #[must_use]
+    pub fn to_variable_set(&self) -> [u8; VARIABLE_SET_BYTES] {
+        let mut bytes = [0; VARIABLE_SET_BYTES];
+        for (value, parameter) in bytes.chunks_exact_mut(2).zip(ConfigParameter::ALL) {
+            value.copy_from_slice(&self.value(parameter).to_le_bytes());
+        }
+        bytes
+    }
+}
+
+/// Stored variable set, padded to the flash write size.
+const CONFIG_BYTES: usize = VARIABLE_SET_BYTES.next_multiple_of(8);
+/// The payout configuration uses storage pages 6 and 7.
+static CONFIG_JOURNAL: Journal<{ CONFIG_BYTES + RECORD_OVERHEAD }> = Journal::new(6);
+
+static PAYOUT_CONFIG: Mutex<CriticalSectionRawMutex, Cell<PayoutConfig>> =
+    Mutex::new(Cell::new(HOPPER_PROFILE.payout_config));
+
+/// Returns the payout configuration in use.
+pub fn payout_config() -> PayoutConfig {
+    PAYOUT_CONFIG.lock(Cell::get)
+}
+
+/// Loads the stored payout configuration, the defaults of the hopper profile stay in use if none
+/// is stored or it is no longer valid for this firmware.
+pub async fn load_payout_config() {
+    let mut payload = [0xFF; CONFIG_BYTES];
+    match CONFIG_JOURNAL.load(&mut payload).await {
+        Ok(true) => {}
+        Ok(false) => {
+            info!("no payout configuration stored, using the defaults");
+            return;
+        }
+        Err(error) => {
+            error!("failed to load the payout configuration: {}", error);
+            return;
+        }
+    }
+
+    match PayoutConfig::from_variable_set(&payload[..VARIABLE_SET_BYTES]) {
+        Ok(config) => {
+            PAYOUT_CONFIG.lock(|current| current.set(config));
+            info!("payout configuration loaded: {}", config);
+        }
+        Err(error) => warn!(
+            "stored payout configuration rejected, using the defaults: {}",
+            error
+        ),
+    }
+}
+
+/// Validates a variable set from the host, saves it and puts it in use.
+///
+/// # Errors
+///
+/// If the set is invalid, the motor is running or the flash cannot be written. The configuration
+/// in use is then unchanged.
+pub async fn set_payout_config(bytes: &[u8]) -> Result<(), ConfigError> {
+    let config = PayoutConfig::from_variable_set(bytes)?;
+    if is_motor_running().await {
+        warn!("payout configuration not changed, the motor is running");
+        return Err(ConfigError::Busy);
+    }
+
+    let mut payload = [0xFF; CONFIG_BYTES];
+    payload[..VARIABLE_SET_BYTES].copy_from_slice(bytes);
+    CONFIG_JOURNAL.save(&payload).await?;
+    PAYOUT_CONFIG.lock(|current| current.set(config));
+
+    info!("payout configuration changed: {}", config);
+    log_event(Event::ConfigChanged);
+    Ok(())
+}
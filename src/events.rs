@@ -0,0 +1,121 @@
+use core::cell::RefCell;
+
+use defmt::info;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Instant;
+use heapless::Deque;
+
+use crate::faults::Fault;
+
+/// Number of events kept in the log, the oldest one is dropped when it is full.
+pub const EVENT_LOG_CAPACITY: usize = 32;
+
+/// Noteworthy things that happened on the hopper, kept for the host to read through a handheld
+/// function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Event {
+    /// Coins went through the exit sensor after the motor was stopped.
+    Overpaid {
+        coins: u8,
+    },
+    /// Coins were added to the hopper, either `counted` by the attendant or derived from the
+    /// high level plate.
+    Refilled {
+        coins: u16,
+        counted: bool,
+    },
+    /// The audit meters were cleared by the host.
+    AuditCleared,
+    /// Another device on the bus replied from our `address`.
+    AddressClash {
+        address: u8,
+    },
+    /// A payout was ended early because the hopper ran empty.
+    PayoutEndedEmpty {
+        unpaid: u8,
+    },
+    /// The payout configuration was changed by the host.
+    ConfigChanged,
+    /// The ccTalk PIN was changed or, when set to zero, disabled.
+    PinChanged,
+    /// Too many wrong PINs were entered, PIN entry is ignored for a while.
+    PinLockout,
+    /// A firmware image was received and verified, the bootloader swaps it in on restart.
+    FirmwareUpgraded,
+    /// The new firmware reset before it was confirmed, the bootloader restored the previous one.
+    FirmwareRolledBack,
+    /// A serial number was programmed in OTP at manufacturing.
+    SerialProvisioned,
+    /// The controller was reset by the watchdog after a task stalled.
+    WatchdogReset,
+    Fault(Fault),
+}
+
+impl Event {
+    /// Longest encoding of an event.
+    pub const MAX_ENCODED_LEN: usize = 4;
+
+    /// Encodes the event for the host as `[ code ] [ data ] ..`, codes are numbered from 1 in
+    /// declaration order and a fault is followed by its own code, see [`Fault::code`].
+    #[must_use]
+    pub fn encode(self) -> heapless::Vec<u8, { Self::MAX_ENCODED_LEN }> {
+        let bytes: &[u8] = match self {
+            Self::Overpaid { coins } => &[1, coins],
+            Self::Refilled { coins, counted } => {
+                let [lsb, msb] = coins.to_le_bytes();
+                &[2, lsb, msb, u8::from(counted)]
+            }
+            Self::AuditCleared => &[3],
+            Self::AddressClash { address } => &[4, address],
+            Self::PayoutEndedEmpty { unpaid } => &[5, unpaid],
+            Self::ConfigChanged => &[6],
+            Self::PinChanged => &[7],
+            Self::PinLockout => &[8],
+            Self::FirmwareUpgraded => &[9],
+            Self::FirmwareRolledBack => &[10],
+            Self::SerialProvisioned => &[11],
+            Self::WatchdogReset => &[12],
+            Self::Fault(fault) => &[13, fault.code()],
+        };
+        // Cannot fail, no encoding is longer than `MAX_ENCODED_LEN`.
+        heapless::Vec::from_slice(bytes).unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct EventRecord {
+    /// Time since the controller started.
+    pub timestamp: Instant,
+    pub event: Event,
+}
+
+static EVENT_LOG: Mutex<CriticalSectionRawMutex, RefCell<Deque<EventRecord, EVENT_LOG_CAPACITY>>> =
+    Mutex::new(RefCell::new(Deque::new()));
+
+/// Appends an event to the log, dropping the oldest one if needed.
+pub fn log_event(event: Event) {
+    let record = EventRecord {
+        timestamp: Instant::now(),
+        event,
+    };
+    info!("event: {}", record);
+
+    EVENT_LOG.lock(|log| {
+        let mut log = log.borrow_mut();
+        if log.is_full() {
+            log.pop_front();
+        }
+        // Cannot fail, room was made above.
+        let _ = log.push_back(record);
+    });
+}
+
+/// Returns the `index`th most recent event, `0` being the latest.
+pub fn recent_event(index: usize) -> Option<EventRecord> {
+    EVENT_LOG.lock(|log| {
+        let log = log.borrow();
+        log.len()
+            .checked_sub(index + 1)
+            .and_then(|position| log.iter().nth(position).copied())
+    })
+}
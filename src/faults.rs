@@ -0,0 +1,130 @@
+use core::cell::Cell;
+
+use cc_talk_core::cc_talk::HopperFlag;
+use defmt::warn;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+
+use crate::events::{log_event, Event};
+
+/// Faults detected by the adapter itself, on top of what the hopper reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Fault {
+    /// The motor ran longer than allowed for the requested coin count and was forced off.
+    MotorRunTimeExceeded,
+    /// The motor was on while payouts were disabled and was forced off.
+    MotorRunningWhilePayoutDisabled,
+    /// The exit opto stayed blocked while the motor was off.
+    OptoBlockedDuringIdle,
+    /// A coin went through the exit opto while the motor was off, usually one dropping late
+    /// after a payout.
+    CoinDuringIdle,
+    /// The exit opto chattered while the motor was off.
+    OptoChatterDuringIdle,
+    /// The exit opto stayed blocked for longer than a coin would during a payout, a jam.
+    OptoBlockedDuringPayout,
+    /// The exit opto chattered during a payout. The coins are moving then, so this is taken for a
+    /// faulty opto or wiring rather than a fraud attempt.
+    OptoChatterDuringPayout,
+    /// The high level plate is covered while the low level plate is not, one of them is stuck.
+    LevelSensorsImplausible,
+}
+
+impl Fault {
+    /// The ccTalk test hopper flag used to report this fault to the host, if there is one.
+    const fn hopper_flag(self) -> Option<HopperFlag> {
+        match self {
+            Self::MotorRunTimeExceeded | Self::MotorRunningWhilePayoutDisabled => {
+                Some(HopperFlag::PayoutTimeoutOccurred)
+            }
+            Self::OptoBlockedDuringIdle => Some(HopperFlag::OptoFraudPathBlockedDuringIdle),
+            Self::OptoChatterDuringIdle => Some(HopperFlag::OptoFraudShortCircuitDuringIdle),
+            Self::OptoBlockedDuringPayout => Some(HopperFlag::OptoBlockedPermanentlyDuringPayout),
+            Self::CoinDuringIdle
+            | Self::OptoChatterDuringPayout
+            | Self::LevelSensorsImplausible => None,
+        }
+    }
+
+    /// Code of the fault in the event log read by the host, numbered from 1 in declaration order.
+    #[must_use]
+    pub const fn code(self) -> u8 {
+        match self {
+            Self::MotorRunTimeExceeded => 1,
+            Self::MotorRunningWhilePayoutDisabled => 2,
+            Self::OptoBlockedDuringIdle => 3,
+            Self::CoinDuringIdle => 4,
+            Self::OptoChatterDuringIdle => 5,
+            Self::OptoBlockedDuringPayout => 6,
+            Self::OptoChatterDuringPayout => 7,
+            Self::LevelSensorsImplausible => 8,
+        }
+    }
+
+    /// Whether this fault is a possible fraud attempt. Like on ccTalk hoppers, these block
+    /// payouts until the next reset. A jam, a stray coin or a sensor fault only raises its flag,
+    /// if it has one.
+    const fn is_fraud(self) -> bool {
+        match self {
+            Self::MotorRunTimeExceeded
+            | Self::MotorRunningWhilePayoutDisabled
+            | Self::CoinDuringIdle
+            | Self::OptoBlockedDuringPayout
+            | Self::OptoChatterDuringPayout
+            | Self::LevelSensorsImplausible => false,
+            Self::OptoBlockedDuringIdle | Self::OptoChatterDuringIdle => true,
+        }
+    }
+}
+
+/// Test hopper registers (header 163) raised since the last controller reset.
+static HOPPER_REGISTERS: Mutex<CriticalSectionRawMutex, Cell<[u8; 3]>> =
+    Mutex::new(Cell::new([0; 3]));
+static LAST_FAULT: Mutex<CriticalSectionRawMutex, Cell<Option<Fault>>> =
+    Mutex::new(Cell::new(None));
+/// Fraud attempts detected since the controller started.
+static FRAUD_ATTEMPTS: Mutex<CriticalSectionRawMutex, Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// Records a fault and raises its ccTalk flag until the next controller reset, which the reset
+/// of header 1 also does.
+pub fn record_fault(fault: Fault) {
+    warn!("fault recorded: {}", fault);
+    LAST_FAULT.lock(|last| last.set(Some(fault)));
+    log_event(Event::Fault(fault));
+    if let Some(flag) = fault.hopper_flag() {
+        raise_flag(flag);
+    }
+    if fault.is_fraud() {
+        FRAUD_ATTEMPTS.lock(|attempts| attempts.set(attempts.get().saturating_add(1)));
+    }
+}
+
+/// Raises a ccTalk test hopper flag.
+pub fn raise_flag(flag: HopperFlag) {
+    let [register, bit] = (flag as u16).to_be_bytes();
+    HOPPER_REGISTERS.lock(|registers| {
+        let mut value = registers.get();
+        value[usize::from(register)] |= bit;
+        registers.set(value);
+    });
+}
+
+/// Returns the three test hopper registers.
+pub fn hopper_registers() -> [u8; 3] {
+    HOPPER_REGISTERS.lock(Cell::get)
+}
+
+/// Returns the last fault recorded since the controller started.
+pub fn last_fault() -> Option<Fault> {
+    LAST_FAULT.lock(Cell::get)
+}
+
+/// Returns the number of fraud attempts detected since the controller started, read by the host
+/// through a handheld function.
+pub fn fraud_attempts() -> u32 {
+    FRAUD_ATTEMPTS.lock(Cell::get)
+}
+
+/// Whether a fraud fault was recorded, payouts stay inhibited until the controller is reset.
+pub fn payout_inhibited() -> bool {
+    FRAUD_ATTEMPTS.lock(Cell::get) > 0
+}
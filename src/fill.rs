@@ -0,0 +1,128 @@
+use core::cell::Cell;
+
+use defmt::{debug, info};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+
+use crate::level::{HIGH_LEVEL_SENSOR_CONFIG, LOW_LEVEL_SENSOR_CONFIG};
+
+/// Coins left in the hopper when the low level plate uncovers, set at build time through
+/// `HOPPER_LOW_LEVEL_COINS`.
+pub const LOW_LEVEL_COINS: u16 = parse_coin_count(option_env!("HOPPER_LOW_LEVEL_COINS"), 80);
+/// Coins in the hopper when the high level plate gets covered, set at build time through
+/// `HOPPER_HIGH_LEVEL_COINS`.
+pub const HIGH_LEVEL_COINS: u16 = parse_coin_count(option_env!("HOPPER_HIGH_LEVEL_COINS"), 800);
+
+const fn parse_coin_count(value: Option<&str>, default: u16) -> u16 {
+    let Some(value) = value else {
+        return default;
+    };
+
+    let bytes = value.as_bytes();
+    assert!(!bytes.is_empty(), "coin count must not be empty");
+    let mut count: u16 = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        assert!(bytes[i].is_ascii_digit(), "coin count must be a number");
+        let Some(shifted) = count.checked_mul(10) else {
+            panic!("coin count must fit in 16 bits");
+        };
+        let Some(next) = shifted.checked_add((bytes[i] - b'0') as u16) else {
+            panic!("coin count must fit in 16 bits");
+        };
+        count = next;
+        i += 1;
+    }
+    count
+}
+
+/// Estimated number of coins in the hopper.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct FillEstimate {
+    pub coins: u16,
+    /// Whether the estimate was set by a level plate transition or by the host. Until then it is
+    /// only a bound derived from the plate states at startup.
+    pub calibrated: bool,
+}
+
+static FILL_ESTIMATE: Mutex<CriticalSectionRawMutex, Cell<FillEstimate>> =
+    Mutex::new(Cell::new(FillEstimate {
+        coins: 0,
+        calibrated: false,
+    }));
+
+/// Returns the estimated number of coins in the hopper.
+pub fn fill_estimate() -> FillEstimate {
+    FILL_ESTIMATE.lock(Cell::get)
+}
+
+fn update(update: impl FnOnce(FillEstimate) -> FillEstimate) -> FillEstimate {
+    FILL_ESTIMATE.lock(|estimate| {
+        let updated = update(estimate.get());
+        estimate.set(updated);
+        updated
+    })
+}
+
+/// Seeds the estimate from the level plates at startup.
+///
+/// Below the low level plate the hopper is taken as empty and between the plates the low level
+/// count is used, it is better to predict a refill too early than too late.
+pub fn seed_fill_estimate(low_covered: bool, high_covered: bool) {
+    let coins = if HIGH_LEVEL_SENSOR_CONFIG.fitted && high_covered {
+        HIGH_LEVEL_COINS
+    } else if LOW_LEVEL_SENSOR_CONFIG.fitted && !low_covered {
+        0
+    } else {
+        LOW_LEVEL_COINS
+    };
+    let estimate = update(|_| FillEstimate {
+        coins,
+        calibrated: false,
+    });
+    info!("initial fill estimate: {}", estimate);
+}
+
+/// Calibrates the estimate on a debounced low level plate transition.
+pub fn calibrate_at_low_level() {
+    let estimate = update(|_| FillEstimate {
+        coins: LOW_LEVEL_COINS,
+        calibrated: true,
+    });
+    debug!("fill estimate calibrated at low level: {}", estimate);
+}
+
+/// Calibrates the estimate on a debounced high level plate transition.
+pub fn calibrate_at_high_level() {
+    let estimate = update(|_| FillEstimate {
+        coins: HIGH_LEVEL_COINS,
+        calibrated: true,
+    });
+    debug!("fill estimate calibrated at high level: {}", estimate);
+}
+
+/// Sets the estimate to a count given by the host, e.g. after counting the hopper contents.
+pub fn set_fill_estimate(coins: u16) {
+    let estimate = update(|_| FillEstimate {
+        coins,
+        calibrated: true,
+    });
+    info!("fill estimate set: {}", estimate);
+}
+
+/// Adds coins put into the hopper, as counted by the host through handheld function 1 or 3. The
+/// adapter has no service console to enter them from.
+pub fn add_coins(coins: u16) {
+    let estimate = update(|estimate| FillEstimate {
+        coins: estimate.coins.saturating_add(coins),
+        ..estimate
+    });
+    info!("{} coins added, fill estimate: {}", coins, estimate);
+}
+
+/// Removes coins seen leaving the hopper on the exit opto.
+pub fn coins_dispensed(coins: u16) {
+    update(|estimate| FillEstimate {
+        coins: estimate.coins.saturating_sub(coins),
+        ..estimate
+    });
+}
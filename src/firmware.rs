@@ -0,0 +1,377 @@
+use core::cell::{Cell, RefCell};
+
+use defmt::{debug, info, warn};
+use embassy_boot::{
+    AlignedBuffer, BlockingFirmwareUpdater, FirmwareUpdaterConfig, FirmwareUpdaterError, State,
+};
+use embassy_stm32::flash::WRITE_SIZE;
+use embassy_sync::{
+    blocking_mutex::{
+        raw::{CriticalSectionRawMutex, ThreadModeRawMutex},
+        Mutex as BlockingMutex,
+    },
+    mutex::Mutex,
+    once_lock::OnceLock,
+};
+use embassy_time::{Duration, Timer};
+use embedded_storage::nor_flash::{NorFlash, NorFlashError, ReadNorFlash};
+
+use crate::{
+    audit::flush_audit_counters,
+    events::{log_event, Event},
+    payout::{get_payout_status, is_motor_running},
+    storage::{crc32_update, with_flash, StorageError, PAGE_SIZE},
+};
+
+/// Bytes in a firmware line (header 140), the host pads the last one with `0xFF`.
+pub const LINE_SIZE: usize = 128;
+/// Lines in a firmware block, the line offset is `(block * 256 + line) * 128`.
+const LINES_PER_BLOCK: u32 = 256;
+/// Time left for the last reply to leave the UART before restarting into the bootloader.
+const RESTART_DELAY: Duration = Duration::from_millis(20);
+/// Run time after which a new image confirms itself without a host, as on the repair bench. The
+/// watchdog resets the controller first if a task stalls.
+const TRIAL_TIME: Duration = Duration::from_secs(60);
+
+/// Bytes in front of the vector table for the header written by `tools/sign_image.py`:
+/// `[ magic ] [ body length LSB ] .. [ MSB ] [ SHA-512 of the body ] [ Ed25519 signature ]`
+const IMAGE_HEADER_SIZE: usize = 256;
+const IMAGE_MAGIC: [u8; 4] = *b"UHAI";
+const IMAGE_DIGEST_OFFSET: usize = 8;
+/// Leading bytes of the digest reported as the image hash.
+const IMAGE_HASH_BYTES: usize = 4;
+
+/// Placeholder for the image header, the signing tool overwrites it in the binary.
+#[used]
+#[link_section = ".image_header"]
+static IMAGE_HEADER: [u8; IMAGE_HEADER_SIZE] = [0xFF; IMAGE_HEADER_SIZE];
+
+static IMAGE_HASH: OnceLock<Option<heapless::String<{ IMAGE_HASH_BYTES * 2 }>>> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum FirmwareError {
+    /// No upgrade was begun with header 139.
+    NotStarted,
+    /// The line does not hold [`LINE_SIZE`] bytes, or the image length is past the lines
+    /// received.
+    InvalidLength,
+    /// The line is neither the next one nor a repeat of the last one.
+    OutOfSequence,
+    /// The image does not fit in the application partition.
+    TooLarge,
+    /// The image read back does not match the CRC-32 given by the host.
+    CrcMismatch,
+    /// The running image was not confirmed yet, the update slot still holds the previous one.
+    NotConfirmed,
+    /// Writing flash stalls the CPU, it is not done while the motor runs.
+    Busy,
+    Flash,
+    Storage(StorageError),
+}
+
+impl From<StorageError> for FirmwareError {
+    fn from(error: StorageError) -> Self {
+        Self::Storage(error)
+    }
+}
+
+impl From<FirmwareUpdaterError> for FirmwareError {
+    fn from(error: FirmwareUpdaterError) -> Self {
+        match error {
+            FirmwareUpdaterError::BadState => Self::NotConfirmed,
+            FirmwareUpdaterError::Flash(_) | FirmwareUpdaterError::Signature(_) => Self::Flash,
+        }
+    }
+}
+
+fn flash_error(error: impl NorFlashError) -> FirmwareError {
+    FirmwareUpdaterError::from(error).into()
+}
+
+/// Offset of the next line expected in the update slot, `None` until an upgrade is begun.
+static NEXT_OFFSET: Mutex<ThreadModeRawMutex, Option<u32>> = Mutex::new(None);
+/// Set while the image was just swapped in by the bootloader and not confirmed yet.
+static ON_TRIAL: BlockingMutex<CriticalSectionRawMutex, Cell<bool>> =
+    BlockingMutex::new(Cell::new(false));
+/// Set once an image was verified, the controller restarts after the reply.
+static RESTART_PENDING: BlockingMutex<CriticalSectionRawMutex, Cell<bool>> =
+    BlockingMutex::new(Cell::new(false));
+
+/// Leading digits of the SHA-512 of the running image in hex, `None` if it was not signed.
+///
+/// The digest is read from the header written by the signing tool. A bootloader built with
+/// `signed-images` only starts an image whose digest and signature check out.
+pub fn image_hash() -> Option<&'static str> {
+    IMAGE_HASH
+        .get_or_init(|| {
+            // The header is written after linking, the compiler must not assume the placeholder.
+            let header_byte = |i: usize| {
+                // SAFETY: `i` is within the header, which is never written at run time.
+                unsafe { core::ptr::read_volatile(IMAGE_HEADER.as_ptr().add(i)) }
+            };
+            if (0..IMAGE_MAGIC.len()).any(|i| header_byte(i) != IMAGE_MAGIC[i]) {
+                return None;
+            }
+
+            let mut hash = heapless::String::new();
+            for i in 0..IMAGE_HASH_BYTES {
+                let byte = header_byte(IMAGE_DIGEST_OFFSET + i);
+                for nibble in [byte >> 4, byte & 0x0F] {
+                    let digit = char::from_digit(u32::from(nibble), 16).unwrap_or('0');
+                    hash.push(digit).ok()?;
+                }
+            }
+            Some(hash)
+        })
+        .as_deref()
+}
+
+/// Reads the bootloader state at startup.
+///
+/// A freshly swapped image is on trial until [`confirm_boot`] or [`trial_task`], the bootloader
+/// swaps the previous image back if the controller resets before. A rollback is logged and
+/// acknowledged here.
+pub async fn check_boot() {
+    let result = with_flash(|flash| {
+        let flash = BlockingMutex::new(RefCell::new(flash));
+        let config = FirmwareUpdaterConfig::from_linkerfile_blocking(&flash, &flash);
+        let mut aligned = AlignedBuffer([0; WRITE_SIZE]);
+        let mut updater = BlockingFirmwareUpdater::new(config, &mut aligned.0);
+        let state = updater.get_state()?;
+        if state == State::Revert {
+            updater.mark_booted()?;
+        }
+        Ok::<_, FirmwareError>(state)
+    })
+    .await;
+
+    match result {
+        Ok(State::Swap) => {
+            info!("new firmware on trial until confirmed");
+            ON_TRIAL.lock(|on_trial| on_trial.set(true));
+        }
+        Ok(State::Revert) => {
+            warn!("new firmware failed to start, the previous one was restored");
+            log_event(Event::FirmwareRolledBack);
+        }
+        Ok(_) => {}
+        Err(error) => warn!("failed to read the bootloader state: {}", error),
+    }
+}
+
+/// Confirms an image on trial, called once the host got a reply from it.
+pub async fn confirm_boot() {
+    if !ON_TRIAL.lock(Cell::get) {
+        return;
+    }
+
+    let result = with_flash(|flash| {
+        let flash = BlockingMutex::new(RefCell::new(flash));
+        let config = FirmwareUpdaterConfig::from_linkerfile_blocking(&flash, &flash);
+        let mut aligned = AlignedBuffer([0; WRITE_SIZE]);
+        BlockingFirmwareUpdater::new(config, &mut aligned.0)
+            .mark_booted()
+            .map_err(FirmwareError::from)
+    })
+    .await;
+
+    match result {
+        Ok(()) => {
+            info!("new firmware confirmed");
+            ON_TRIAL.lock(|on_trial| on_trial.set(false));
+        }
+        Err(error) => warn!("failed to confirm the new firmware: {}", error),
+    }
+}
+
+/// Confirms an image on trial once it ran for [`TRIAL_TIME`], if no host answered it before.
+#[embassy_executor::task]
+pub async fn trial_task() {
+    if ON_TRIAL.lock(Cell::get) {
+        Timer::after(TRIAL_TIME).await;
+        confirm_boot().await;
+    }
+}
+
+/// Starts receiving an image into the update slot (header 139).
+///
+/// # Errors
+///
+/// If the running image is still on trial, the motor is running or the bootloader state cannot
+/// be read.
+pub async fn begin_upgrade() -> Result<(), FirmwareError> {
+    if ON_TRIAL.lock(Cell::get) {
+        return Err(FirmwareError::NotConfirmed);
+    }
+    if is_motor_running().await {
+        return Err(FirmwareError::Busy);
+    }
+
+    let mut next_offset = NEXT_OFFSET.lock().await;
+    *next_offset = None;
+    with_flash(|flash| {
+        let flash = BlockingMutex::new(RefCell::new(flash));
+        let config = FirmwareUpdaterConfig::from_linkerfile_blocking(&flash, &flash);
+        let mut aligned = AlignedBuffer([0; WRITE_SIZE]);
+        match BlockingFirmwareUpdater::new(config, &mut aligned.0).get_state()? {
+            State::Swap => Err(FirmwareError::NotConfirmed),
+            _ => Ok(()),
+        }
+    })
+    .await?;
+    *next_offset = Some(0);
+    drop(next_offset);
+
+    info!("firmware upgrade begun");
+    Ok(())
+}
+
+/// Writes a line of the image (header 140).
+///
+/// Lines are written in order, the pages of the update slot are erased as they are reached. A
+/// repeat of the last line is accepted if it matches, for when its ACK was lost.
+///
+/// # Errors
+///
+/// If no upgrade was begun, the line is out of sequence or past the application partition, the
+/// motor is running or the flash cannot be written.
+pub async fn write_line(block: u8, line: u8, data: &[u8]) -> Result<(), FirmwareError> {
+    if data.len() != LINE_SIZE {
+        return Err(FirmwareError::InvalidLength);
+    }
+    let offset = (u32::from(block) * LINES_PER_BLOCK + u32::from(line)) * LINE_SIZE as u32;
+
+    let mut next_offset = NEXT_OFFSET.lock().await;
+    let next = (*next_offset).ok_or(FirmwareError::NotStarted)?;
+    if is_motor_running().await {
+        return Err(FirmwareError::Busy);
+    }
+
+    let next = with_flash(|flash| {
+        let flash = BlockingMutex::new(RefCell::new(flash));
+        let mut dfu = FirmwareUpdaterConfig::from_linkerfile_blocking(&flash, &flash).dfu;
+        if offset + LINE_SIZE as u32 == next {
+            let mut written = [0; LINE_SIZE];
+            dfu.read(offset, &mut written).map_err(flash_error)?;
+            return if written == data {
+                Ok(next)
+            } else {
+                Err(FirmwareError::OutOfSequence)
+            };
+        }
+        if offset != next {
+            return Err(FirmwareError::OutOfSequence);
+        }
+        // The update slot has one page more than the application partition, for the swap.
+        if (offset + LINE_SIZE as u32) as usize > dfu.capacity() - PAGE_SIZE as usize {
+            return Err(FirmwareError::TooLarge);
+        }
+        if offset.is_multiple_of(PAGE_SIZE) {
+            dfu.erase(offset, offset + PAGE_SIZE).map_err(flash_error)?;
+        }
+        dfu.write(offset, data).map_err(flash_error)?;
+        Ok(next + LINE_SIZE as u32)
+    })
+    .await?;
+    *next_offset = Some(next);
+    drop(next_offset);
+    Ok(())
+}
+
+/// Verifies the image received and has the bootloader swap it in on the next start (header
+/// 138). The controller restarts once the reply is sent, see [`restart_if_pending`].
+///
+/// The host sends the image length and CRC-32 along, which the standard header leaves out:
+/// `[ length LSB ] .. [ length MSB ] [ CRC LSB ] .. [ CRC MSB ]`
+///
+/// # Errors
+///
+/// If no upgrade was begun, `length` is past the lines received, the image does not match `crc`
+/// or the bootloader state cannot be written. The lines received are kept, the host can finish
+/// again.
+pub async fn finish_upgrade(length: u32, crc: u32) -> Result<(), FirmwareError> {
+    let next_offset = NEXT_OFFSET.lock().await;
+    let next = (*next_offset).ok_or(FirmwareError::NotStarted)?;
+    if length == 0 || length > next {
+        return Err(FirmwareError::InvalidLength);
+    }
+
+    with_flash(|flash| {
+        let flash = BlockingMutex::new(RefCell::new(flash));
+        let config = FirmwareUpdaterConfig::from_linkerfile_blocking(&flash, &flash);
+        let mut aligned = AlignedBuffer([0; WRITE_SIZE]);
+        let mut updater = BlockingFirmwareUpdater::new(config, &mut aligned.0);
+
+        let mut running = !0;
+        let mut chunk = [0; LINE_SIZE];
+        for offset in (0..length).step_by(LINE_SIZE) {
+            let chunk = &mut chunk[..LINE_SIZE.min((length - offset) as usize)];
+            updater.read_dfu(offset, chunk)?;
+            running = crc32_update(running, chunk);
+        }
+        if !running != crc {
+            return Err(FirmwareError::CrcMismatch);
+        }
+        updater.mark_updated()?;
+        Ok(())
+    })
+    .await?;
+    drop(next_offset);
+
+    info!("firmware image of {} bytes verified", length);
+    log_event(Event::FirmwareUpgraded);
+    RESTART_PENDING.lock(|pending| pending.set(true));
+    Ok(())
+}
+
+/// Asks the bootloader to stay in USB DFU mode until an image is downloaded (handheld function
+/// 11). The controller restarts once the reply is sent, see [`restart_if_pending`].
+///
+/// # Errors
+///
+/// If the running image is still on trial, the motor is running or the bootloader state cannot
+/// be written.
+#[cfg(feature = "usb")]
+pub async fn request_usb_dfu() -> Result<(), FirmwareError> {
+    // The bootloader would not swap back an image on trial that failed to confirm.
+    if ON_TRIAL.lock(Cell::get) {
+        return Err(FirmwareError::NotConfirmed);
+    }
+    if is_motor_running().await {
+        return Err(FirmwareError::Busy);
+    }
+
+    with_flash(|flash| {
+        let flash = BlockingMutex::new(RefCell::new(flash));
+        let config = FirmwareUpdaterConfig::from_linkerfile_blocking(&flash, &flash);
+        let mut aligned = AlignedBuffer([0; WRITE_SIZE]);
+        BlockingFirmwareUpdater::new(config, &mut aligned.0)
+            .mark_dfu()
+            .map_err(FirmwareError::from)
+    })
+    .await?;
+
+    info!("USB DFU mode requested");
+    RESTART_PENDING.lock(|pending| pending.set(true));
+    Ok(())
+}
+
+/// Restarts into the bootloader once an upgrade finished or USB DFU mode was requested, called
+/// after each reply.
+///
+/// The restart is postponed to a later reply while the motor runs or coins are left to pay out,
+/// the audit meters are saved before it.
+pub async fn restart_if_pending() {
+    if !RESTART_PENDING.lock(Cell::get) {
+        return;
+    }
+    if is_motor_running().await || get_payout_status().await.coins_remaining > 0 {
+        debug!("restart postponed until the payout ends");
+        return;
+    }
+
+    flush_audit_counters().await;
+    info!("restarting into the bootloader");
+    Timer::after(RESTART_DELAY).await;
+    cortex_m::peripheral::SCB::sys_reset();
+}
@@ -1,59 +1,80 @@
 use cc_talk_core::cc_talk::{
-    Category, ChecksumType, DataStorage, Device, HopperDispenseStatus, HopperStatus, Manufacturer,
-    MemoryType, SerialCode,
+    Category, ChecksumType, DataStorage, Device, Header, HopperDispenseStatus, HopperFlag,
+    HopperStatus, Manufacturer, MemoryType, SerialCode,
 };
 use cc_talk_device::device_impl::{DeviceImpl, SimplePayoutDevice};
-use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, mutex::Mutex};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, mutex::Mutex, once_lock::OnceLock};
 
+#[cfg(feature = "bootloader")]
+use crate::firmware::image_hash;
 use crate::{
     build_info,
+    faults::hopper_registers,
+    level::get_sensor_status,
     payout::{
-        enable_payout, get_dispense_count, get_payout_status, get_sensor_status, request_payout,
+        emergency_stop, enable_payout, get_dispense_count, get_payout_status, request_payout,
     },
     reset::{send_reset_signal, ResetType},
+    serial::serial_number,
+    user_data::{USER_DATA_BLOCKS, USER_DATA_BLOCK_SIZE},
+    watchdog::{last_reset_cause, ResetCause},
 };
 
 static BUS_ADDRESS: Mutex<ThreadModeRawMutex, u8> = Mutex::new(3);
 
-const fn parse_serial_code() -> (u8, u8, u8) {
-    const SERIAL_STR: &str = match option_env!("HOPPER_SERIAL_CODE") {
-        Some(s) => s,
-        None => "0,215,0",
-    };
-
-    let bytes = SERIAL_STR.as_bytes();
-    let mut a: u8 = 0;
-    let mut b: u8 = 0;
-    let mut c: u8 = 0;
-    let mut i = 0;
-    let mut field = 0;
-
-    while i < bytes.len() {
-        if bytes[i] >= b'0' && bytes[i] <= b'9' {
-            let digit = bytes[i] - b'0';
-            match field {
-                0 => a = a * 10 + digit,
-                1 => b = b * 10 + digit,
-                2 => c = c * 10 + digit,
-                _ => {}
-            }
-        } else if bytes[i] == b',' {
-            field += 1;
-        }
-        i += 1;
-    }
-
-    // TODO: make SerialCode::new const fn and return SerialCode directly
-    (a, b, c)
+/// Version prefixed with `V`, e.g. `V1.1.0`.
+static SOFTWARE_REVISION: OnceLock<heapless::String<32>> = OnceLock::new();
+/// Short commit hash, marked `-dirty` when built with uncommitted changes.
+static BUILD_CODE: OnceLock<heapless::String<32>> = OnceLock::new();
+
+/// Joins `parts` for a revision reply, followed by the image hash when the image was signed to
+/// run behind the bootloader, so auditors can match it against the release.
+fn revision_string(parts: &[&str]) -> heapless::String<32> {
+    let mut text = heapless::String::new();
+    // All of it fits, the parts are a few characters each and the hash 8.
+    for part in parts {
+        let _ = text.push_str(part);
+    }
+    #[cfg(feature = "bootloader")]
+    if let Some(hash) = image_hash() {
+        let _ = text.push(' ');
+        let _ = text.push_str(hash);
+    }
+    text
 }
 
 pub struct Hopper;
 
+impl Hopper {
+    /// Commands changing the configuration, the fill estimate, the user data or the firmware. They
+    /// go unanswered while a PIN is set and was not entered since the last reset. The handheld
+    /// functions (header 177) are protected one by one, see [`crate::cctalk`].
+    pub const PIN_PROTECTED_HEADERS: [Header; 7] = [
+        Header::ModifyHopperBalance,
+        Header::ModifyVariableSet,
+        Header::WriteDataBlock,
+        Header::EnterNewPinNumber,
+        Header::BeginFirmwareUpgrade,
+        Header::UploadFirmware,
+        Header::FinishFirmwareUpgrade,
+    ];
+
+    /// Whether `header` is in [`Self::PIN_PROTECTED_HEADERS`].
+    #[must_use]
+    pub fn requires_pin(header: Header) -> bool {
+        Self::PIN_PROTECTED_HEADERS.contains(&header)
+    }
+}
+
 pub async fn set_bus_address(address: u8) {
     let mut bus_address = BUS_ADDRESS.lock().await;
     *bus_address = address;
 }
 
+pub async fn get_bus_address() -> u8 {
+    *BUS_ADDRESS.lock().await
+}
+
 impl DeviceImpl for Hopper {
     fn manufacturer(&self) -> Manufacturer {
         Manufacturer::INOTEK
@@ -72,20 +93,35 @@ impl DeviceImpl for Hopper {
     }
 
     fn serial_number(&self) -> SerialCode {
-        let (a, b, c) = parse_serial_code();
-        SerialCode::new(a, b, c)
+        serial_number()
     }
 
     fn software_revision(&self) -> &'static str {
-        build_info::PKG_VERSION
+        SOFTWARE_REVISION.get_or_init(|| revision_string(&["V", build_info::PKG_VERSION]))
     }
 
     fn build_code(&self) -> &'static str {
-        build_info::PKG_VERSION
+        BUILD_CODE.get_or_init(|| {
+            let dirty = if build_info::GIT_DIRTY == Some(true) {
+                "-dirty"
+            } else {
+                ""
+            };
+            revision_string(&[
+                build_info::GIT_COMMIT_HASH_SHORT.unwrap_or("unknown"),
+                dirty,
+            ])
+        })
     }
 
     fn data_storage_availability(&self) -> DataStorage {
-        DataStorage::new(MemoryType::VolatileOnReset, 0, 0, 0, 0)
+        DataStorage::new(
+            MemoryType::PermanentLimitedUse,
+            u16::from(USER_DATA_BLOCKS),
+            USER_DATA_BLOCK_SIZE,
+            u16::from(USER_DATA_BLOCKS),
+            USER_DATA_BLOCK_SIZE,
+        )
     }
 
     fn comms_revision(&self) -> (u8, u8, u8) {
@@ -115,7 +151,7 @@ impl SimplePayoutDevice for Hopper {
     }
 
     async fn emergency_stop(&self) {
-        send_reset_signal(ResetType::Hopper);
+        emergency_stop();
     }
 
     fn request_hopper_coin(&self) -> &'static str {
@@ -139,7 +175,14 @@ impl SimplePayoutDevice for Hopper {
     }
 
     async fn test(&self) -> (u8, u8, u8) {
-        // TODO: implement self test
-        (0, 0, 0)
+        let [mut register_1, register_2, register_3] = hopper_registers();
+        // The host is expected to set the hopper up again after a reset it did not ask for.
+        if matches!(
+            last_reset_cause(),
+            ResetCause::BrownOut | ResetCause::Watchdog
+        ) {
+            register_1 |= HopperFlag::PowerUpDetected as u8;
+        }
+        (register_1, register_2, register_3)
     }
 }
@@ -0,0 +1,184 @@
+use defmt::{debug, info};
+use embassy_futures::select::{select, Either};
+use embassy_stm32::gpio::{Level, Output};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::{
+    cctalk::address_clash_detected,
+    faults::{last_fault, payout_inhibited, Fault},
+    level::is_below_low_level,
+    payout::{is_motor_running, is_payout_enabled, is_security_tripped},
+};
+
+/// Blink timing used to count out numbers on the status LED.
+const COUNT_ON_TIME: Duration = Duration::from_millis(300);
+const COUNT_OFF_TIME: Duration = Duration::from_millis(300);
+/// Blink timing of the fault codes, a code is `n` short blinks followed by a pause.
+const CODE_ON_TIME: Duration = Duration::from_millis(150);
+const CODE_OFF_TIME: Duration = Duration::from_millis(250);
+const CODE_PAUSE: Duration = Duration::from_millis(1_500);
+/// Length of the flash shown for a ccTalk reply.
+const COMMS_FLASH_TIME: Duration = Duration::from_millis(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum LedCommand {
+    /// Blinks the ccTalk bus address, one blink per unit.
+    ShowAddress(u8),
+}
+
+/// What the status LED shows, derived from the state kept by the payout tasks. Faults take
+/// precedence over the operating states.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum StatusPattern {
+    /// Mostly on, with a short off blink every two seconds.
+    IdleOk,
+    /// Fast even blinking.
+    PayingOut,
+    /// Slow even blinking.
+    PayoutsDisabled,
+    /// Code of two blinks.
+    HopperEmpty,
+    /// Code of three blinks.
+    Jam,
+    /// Code of four blinks, while the hopper security output is tripped or payouts are
+    /// inhibited by a fraud attempt.
+    SecurityFault,
+    /// Code of five blinks.
+    AddressClash,
+}
+
+impl StatusPattern {
+    /// Picks the pattern for the current state of the adapter.
+    pub async fn current() -> Self {
+        if address_clash_detected() {
+            Self::AddressClash
+        } else if last_fault() == Some(Fault::OptoBlockedDuringPayout) {
+            Self::Jam
+        } else if is_security_tripped().await || payout_inhibited() {
+            Self::SecurityFault
+        } else if is_motor_running().await {
+            Self::PayingOut
+        } else if is_below_low_level().await {
+            Self::HopperEmpty
+        } else if !is_payout_enabled().await {
+            Self::PayoutsDisabled
+        } else {
+            Self::IdleOk
+        }
+    }
+
+    const fn blink_code(self) -> Option<u8> {
+        match self {
+            Self::HopperEmpty => Some(2),
+            Self::Jam => Some(3),
+            Self::SecurityFault => Some(4),
+            Self::AddressClash => Some(5),
+            Self::IdleOk | Self::PayingOut | Self::PayoutsDisabled => None,
+        }
+    }
+
+    /// On and off times of the patterns that are not blink codes.
+    const fn on_off_times(self) -> (Duration, Duration) {
+        match self {
+            Self::PayingOut => (Duration::from_millis(100), Duration::from_millis(100)),
+            Self::PayoutsDisabled => (Duration::from_millis(1_000), Duration::from_millis(1_000)),
+            Self::IdleOk
+            | Self::HopperEmpty
+            | Self::Jam
+            | Self::SecurityFault
+            | Self::AddressClash => (Duration::from_millis(1_900), Duration::from_millis(100)),
+        }
+    }
+}
+
+static LED_SIGNAL: Signal<ThreadModeRawMutex, LedCommand> = Signal::new();
+static COMMS_SIGNAL: Signal<ThreadModeRawMutex, ()> = Signal::new();
+
+/// Blinks the bus address on the status LED.
+pub fn show_address(address: u8) {
+    LED_SIGNAL.signal(LedCommand::ShowAddress(address));
+}
+
+/// Flashes the comms LED, or briefly inverts the status LED without one, for a ccTalk reply.
+pub fn comms_activity() {
+    COMMS_SIGNAL.signal(());
+}
+
+struct Leds {
+    status: Output<'static>,
+    comms: Option<Output<'static>>,
+}
+
+impl Leds {
+    /// Holds the status LED `on` or off for `duration`, flashing comms activity meanwhile.
+    async fn hold(&mut self, on: bool, duration: Duration) {
+        let end = Instant::now() + duration;
+        self.status.set_level(Level::from(on));
+        loop {
+            match select(Timer::at(end), COMMS_SIGNAL.wait()).await {
+                Either::First(()) => return,
+                Either::Second(()) => self.flash_comms(on).await,
+            }
+        }
+    }
+
+    async fn flash_comms(&mut self, status_on: bool) {
+        if let Some(comms) = &mut self.comms {
+            comms.set_high();
+            Timer::after(COMMS_FLASH_TIME).await;
+            comms.set_low();
+        } else {
+            self.status.set_level(Level::from(!status_on));
+            Timer::after(COMMS_FLASH_TIME).await;
+            self.status.set_level(Level::from(status_on));
+        }
+    }
+
+    async fn blink_count(&mut self, count: u8, on_time: Duration, off_time: Duration) {
+        for _ in 0..count {
+            self.hold(true, on_time).await;
+            self.hold(false, off_time).await;
+        }
+    }
+
+    async fn show(&mut self, pattern: StatusPattern) {
+        if let Some(code) = pattern.blink_code() {
+            self.blink_count(code, CODE_ON_TIME, CODE_OFF_TIME).await;
+            self.hold(false, CODE_PAUSE).await;
+        } else {
+            let (on, off) = pattern.on_off_times();
+            self.hold(true, on).await;
+            self.hold(false, off).await;
+        }
+    }
+}
+
+/// Drives the status LED, and the comms LED if the board has one.
+#[embassy_executor::task]
+pub async fn status_led_task(status: Output<'static>, comms: Option<Output<'static>>) {
+    info!("status led task started");
+    let mut leds = Leds { status, comms };
+    let mut last_pattern = None;
+    loop {
+        let pattern = StatusPattern::current().await;
+        if last_pattern != Some(pattern) {
+            debug!("status led pattern: {}", pattern);
+            last_pattern = Some(pattern);
+        }
+
+        let command = match select(leds.show(pattern), LED_SIGNAL.wait()).await {
+            Either::First(()) => continue,
+            Either::Second(command) => command,
+        };
+        match command {
+            LedCommand::ShowAddress(address) => {
+                info!("showing bus address {} on the status led", address);
+                leds.hold(false, CODE_PAUSE).await;
+                leds.blink_count(address, COUNT_ON_TIME, COUNT_OFF_TIME)
+                    .await;
+                leds.hold(false, CODE_PAUSE).await;
+            }
+        }
+    }
+}
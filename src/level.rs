@@ -0,0 +1,284 @@
+use cc_talk_core::cc_talk::HopperStatus;
+use defmt::{debug, info, trace};
+use embassy_futures::select::{select, select4, Either4};
+use embassy_stm32::{exti::ExtiInput, gpio::Level};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, mutex::Mutex, signal::Signal};
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::{
+    faults::{record_fault, Fault},
+    fill::{calibrate_at_high_level, calibrate_at_low_level, seed_fill_estimate},
+    profile::HOPPER_PROFILE,
+    watchdog::{supervised, SupervisedTask},
+};
+
+/// Wiring of a level sensor.
+#[derive(Clone, Copy, Debug, defmt::Format, Eq, PartialEq)]
+pub struct LevelSensorConfig {
+    /// Whether the level plate is fitted on this hopper.
+    pub fitted: bool,
+    /// Level read when the plate is covered by coins.
+    pub active_level: Level,
+}
+
+impl LevelSensorConfig {
+    fn is_covered(self, level: Level) -> bool {
+        self.fitted && level == self.active_level
+    }
+}
+
+/// Low level plate wiring of the hopper profile.
+pub const LOW_LEVEL_SENSOR_CONFIG: LevelSensorConfig = HOPPER_PROFILE.low_level_sensor;
+/// High level plate wiring of the hopper profile.
+pub const HIGH_LEVEL_SENSOR_CONFIG: LevelSensorConfig = HOPPER_PROFILE.high_level_sensor;
+
+/// Sampling period of the level plates while the motor runs, edges are too noisy then.
+const MOTOR_NOISE_SAMPLE_INTERVAL: Duration = Duration::from_millis(20);
+/// Number of samples the majority vote is taken over while the motor runs.
+pub(crate) const MOTOR_NOISE_WINDOW: u8 = 15;
+
+static MOTOR_STATE_SIGNAL: Signal<ThreadModeRawMutex, bool> = Signal::new();
+
+static HIGH_LEVEL_SENSOR: Mutex<ThreadModeRawMutex, LevelSensorReading> =
+    Mutex::new(LevelSensorReading::new());
+static LOW_LEVEL_SENSOR: Mutex<ThreadModeRawMutex, LevelSensorReading> =
+    Mutex::new(LevelSensorReading::new());
+
+/// Debounced state of a level sensor.
+#[derive(Clone, Copy, Debug, defmt::Format, Eq, PartialEq)]
+pub struct LevelSensorReading {
+    /// Whether the plate is covered by coins, always `false` when the plate is not fitted.
+    pub covered: bool,
+    /// Time of the last debounced transition, `None` if the level did not change since startup.
+    pub last_transition: Option<Instant>,
+}
+
+impl LevelSensorReading {
+    const fn new() -> Self {
+        Self {
+            covered: false,
+            last_transition: None,
+        }
+    }
+}
+
+/// Debounces a level sensor, the reported state only changes once the plate has been stable
+/// for the debounce time of the new state.
+pub(crate) struct Debouncer {
+    stable: bool,
+    candidate: bool,
+    since: Instant,
+}
+
+impl Debouncer {
+    pub(crate) fn new(covered: bool) -> Self {
+        Self {
+            stable: covered,
+            candidate: covered,
+            since: Instant::now(),
+        }
+    }
+
+    const fn debounce_time(covered: bool) -> Duration {
+        if covered {
+            HOPPER_PROFILE.level_debounce_covered
+        } else {
+            HOPPER_PROFILE.level_debounce_uncovered
+        }
+    }
+
+    /// Feeds a raw sample, returns the new stable state when it changed.
+    pub(crate) fn sample(&mut self, covered: bool, now: Instant) -> Option<bool> {
+        if covered != self.candidate {
+            self.candidate = covered;
+            self.since = now;
+        }
+
+        if self.candidate != self.stable && now - self.since >= Self::debounce_time(self.candidate)
+        {
+            self.stable = self.candidate;
+            return Some(self.stable);
+        }
+        None
+    }
+
+    /// Time at which the pending level becomes stable, if a change is pending.
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        (self.candidate != self.stable).then(|| self.since + Self::debounce_time(self.candidate))
+    }
+}
+
+/// Majority vote over the last [`MOTOR_NOISE_WINDOW`] samples, filters out the spikes the motor
+/// induces on the level plates.
+pub(crate) struct MajorityFilter {
+    history: u16,
+    len: u8,
+}
+
+impl MajorityFilter {
+    pub(crate) const fn new() -> Self {
+        Self { history: 0, len: 0 }
+    }
+
+    pub(crate) const fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Pushes a sample, returns whether the majority of the window is covered.
+    pub(crate) fn push(&mut self, covered: bool) -> bool {
+        let mask = (1u16 << MOTOR_NOISE_WINDOW) - 1;
+        self.history = ((self.history << 1) | u16::from(covered)) & mask;
+        self.len = (self.len + 1).min(MOTOR_NOISE_WINDOW);
+        self.history.count_ones() * 2 > u32::from(self.len)
+    }
+}
+
+/// Tells the sensor task whether the motor is running, level plates are then sampled through a
+/// majority vote instead of being tracked from their edges.
+pub fn set_motor_running(running: bool) {
+    MOTOR_STATE_SIGNAL.signal(running);
+}
+
+pub async fn get_level_sensors() -> (LevelSensorReading, LevelSensorReading) {
+    let low = *LOW_LEVEL_SENSOR.lock().await;
+    let high = *HIGH_LEVEL_SENSOR.lock().await;
+    (low, high)
+}
+
+/// Whether the low level plate is fitted and reports the hopper as below its level.
+pub async fn is_below_low_level() -> bool {
+    LOW_LEVEL_SENSOR_CONFIG.fitted && !LOW_LEVEL_SENSOR.lock().await.covered
+}
+
+/// Level plates that are not fitted are reported as not supported, and as above their level so
+/// hosts ignoring the supported bits do not see an empty hopper.
+pub async fn get_sensor_status() -> HopperStatus {
+    let (low, high) = get_level_sensors().await;
+    HopperStatus::new(
+        LOW_LEVEL_SENSOR_CONFIG.fitted,
+        low.covered || !LOW_LEVEL_SENSOR_CONFIG.fitted,
+        HIGH_LEVEL_SENSOR_CONFIG.fitted,
+        high.covered,
+    )
+}
+
+async fn store_level(sensor: &Mutex<ThreadModeRawMutex, LevelSensorReading>, covered: bool) {
+    let mut reading = sensor.lock().await;
+    reading.covered = covered;
+    reading.last_transition = Some(Instant::now());
+}
+
+/// A full high plate with an empty low plate cannot happen, one of the plates is stuck or wired
+/// with the wrong polarity.
+fn check_plausibility(low_covered: bool, high_covered: bool) {
+    if LOW_LEVEL_SENSOR_CONFIG.fitted
+        && HIGH_LEVEL_SENSOR_CONFIG.fitted
+        && high_covered
+        && !low_covered
+    {
+        record_fault(Fault::LevelSensorsImplausible);
+    }
+}
+
+/// Sleeps until the earliest of the pending debounce deadlines, forever if none is pending.
+async fn debounce_timer(low: &Debouncer, high: &Debouncer) {
+    let deadline = match (low.deadline(), high.deadline()) {
+        (Some(low), Some(high)) => Some(low.min(high)),
+        (low, high) => low.or(high),
+    };
+    match deadline {
+        Some(deadline) => Timer::at(deadline).await,
+        None => core::future::pending().await,
+    }
+}
+
+/// Tracks the level sensors and publishes the debounced levels. While the motor is off the plates
+/// are tracked from their EXTI edges, while it runs they are sampled through a majority vote.
+#[embassy_executor::task]
+pub async fn sensor_task(
+    mut low_level_sensor: ExtiInput<'static>,
+    mut high_level_sensor: ExtiInput<'static>,
+) {
+    info!(
+        "sensor task started, low sensor {}, high sensor {}",
+        LOW_LEVEL_SENSOR_CONFIG, HIGH_LEVEL_SENSOR_CONFIG
+    );
+
+    let low_covered = LOW_LEVEL_SENSOR_CONFIG.is_covered(low_level_sensor.get_level());
+    let high_covered = HIGH_LEVEL_SENSOR_CONFIG.is_covered(high_level_sensor.get_level());
+    let mut motor_running = false;
+
+    info!(
+        "initial sensor levels, low sensor covered {}, high sensor covered {}",
+        low_covered, high_covered
+    );
+    check_plausibility(low_covered, high_covered);
+    seed_fill_estimate(low_covered, high_covered);
+
+    LOW_LEVEL_SENSOR.lock().await.covered = low_covered;
+    HIGH_LEVEL_SENSOR.lock().await.covered = high_covered;
+    let mut low_debouncer = Debouncer::new(low_covered);
+    let mut high_debouncer = Debouncer::new(high_covered);
+    let mut low_filter = MajorityFilter::new();
+    let mut high_filter = MajorityFilter::new();
+
+    info!("initial sensor levels set, starting event loop");
+    loop {
+        let edges = async {
+            if motor_running {
+                core::future::pending::<()>().await;
+            } else {
+                select(
+                    low_level_sensor.wait_for_any_edge(),
+                    high_level_sensor.wait_for_any_edge(),
+                )
+                .await;
+            }
+        };
+        let sampling = async {
+            if motor_running {
+                Timer::after(MOTOR_NOISE_SAMPLE_INTERVAL).await;
+            } else {
+                core::future::pending::<()>().await;
+            }
+        };
+
+        let event = supervised(
+            SupervisedTask::Sensor,
+            select4(
+                edges,
+                MOTOR_STATE_SIGNAL.wait(),
+                sampling,
+                debounce_timer(&low_debouncer, &high_debouncer),
+            ),
+        )
+        .await;
+
+        if let Either4::Second(running) = event {
+            motor_running = running;
+            low_filter.reset();
+            high_filter.reset();
+            debug!("level sensor motor noise filtering: {}", motor_running);
+        }
+
+        let now = Instant::now();
+        let mut low_level = LOW_LEVEL_SENSOR_CONFIG.is_covered(low_level_sensor.get_level());
+        let mut high_level = HIGH_LEVEL_SENSOR_CONFIG.is_covered(high_level_sensor.get_level());
+        if motor_running {
+            low_level = low_filter.push(low_level);
+            high_level = high_filter.push(high_level);
+            trace!("filtered level sensors: {}, {}", low_level, high_level);
+        }
+
+        if let Some(covered) = low_debouncer.sample(low_level, now) {
+            debug!("low level sensor covered: {}", covered);
+            store_level(&LOW_LEVEL_SENSOR, covered).await;
+            calibrate_at_low_level();
+        }
+        if let Some(covered) = high_debouncer.sample(high_level, now) {
+            debug!("high level sensor covered: {}", covered);
+            store_level(&HIGH_LEVEL_SENSOR, covered).await;
+            calibrate_at_high_level();
+        }
+    }
+}
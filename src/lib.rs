@@ -11,10 +11,430 @@ fn panic() -> ! {
     cortex_m::asm::udf();
 }
 
+pub mod audit;
+pub mod board;
+#[allow(clippy::doc_markdown)]
 pub mod build_info;
+pub mod button;
+pub mod cctalk;
+pub mod clocks;
+pub mod config;
+pub mod events;
+pub mod faults;
+pub mod fill;
+#[cfg(all(feature = "signed-images", feature = "stm32g071rb"))]
+compile_error!("the signed bootloader leaves no room for the application on the STM32G071RB");
+// Flash offsets and sizes are 32-bit on the target.
+#[cfg(feature = "bootloader")]
+#[allow(clippy::cast_possible_truncation)]
+pub mod firmware;
 pub mod hopper;
+pub mod led;
+pub mod level;
+pub mod opto;
 pub mod payout;
+pub mod pin;
+pub mod power;
+pub mod profile;
+pub mod refill;
 pub mod reset;
+pub mod serial;
+// Flash offsets and sizes are 32-bit on the target.
+#[allow(clippy::cast_possible_truncation)]
+pub mod storage;
+pub mod user_data;
+pub mod watchdog;
 
 pub type SignalPacket =
     Signal<CriticalSectionRawMutex, Packet<heapless::Vec<u8, MAX_BLOCK_LENGTH>>>;
+
+/// Fixtures of the unit tests, `defmt_test` only takes test functions in its module.
+#[cfg(test)]
+mod test_fixtures {
+    use embassy_futures::block_on;
+
+    use crate::{
+        config::{ConfigParameter, VARIABLE_SET_BYTES},
+        profile::HOPPER_PROFILE,
+        storage::{with_flash, Journal, StorageError, PAGE_SIZE, STORAGE_PAGES},
+    };
+
+    /// Record size of the test journal, one flash write of payload.
+    pub const TEST_RECORD_SIZE: usize = 16;
+    /// Journal on the last two storage pages, those of the payout configuration, see
+    /// [`BorrowedPages`].
+    pub static TEST_JOURNAL: Journal<TEST_RECORD_SIZE> = Journal::new(STORAGE_PAGES - 2);
+
+    /// Sets `parameter` to `value` in a variable set.
+    pub fn set_variable(
+        bytes: &mut [u8; VARIABLE_SET_BYTES],
+        parameter: ConfigParameter,
+        value: u16,
+    ) {
+        let index = ConfigParameter::ALL
+            .iter()
+            .position(|other| *other == parameter)
+            .unwrap_or_default();
+        bytes[index * 2..index * 2 + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// Profile defaults as a variable set, with `parameter` set to `value`.
+    pub fn variable_set_with(parameter: ConfigParameter, value: u16) -> [u8; VARIABLE_SET_BYTES] {
+        let mut bytes = HOPPER_PROFILE.payout_config.to_variable_set();
+        set_variable(&mut bytes, parameter, value);
+        bytes
+    }
+
+    const BORROWED_BYTES: u32 = 2 * PAGE_SIZE;
+
+    /// The pages of the test journal, erased for a test and given back to the payout
+    /// configuration stored there once dropped.
+    #[must_use]
+    pub struct BorrowedPages([u8; BORROWED_BYTES as usize]);
+
+    impl BorrowedPages {
+        /// Saves what the test journal pages hold, then erases them.
+        pub fn erase() -> Self {
+            let mut saved = [0; BORROWED_BYTES as usize];
+            let start = TEST_JOURNAL.page_offset(0);
+            block_on(with_flash(|flash| {
+                flash.blocking_read(start, &mut saved)?;
+                flash
+                    .blocking_erase(start, start + BORROWED_BYTES)
+                    .map_err(StorageError::from)
+            }))
+            .expect("test journal should erase");
+            Self(saved)
+        }
+    }
+
+    impl Drop for BorrowedPages {
+        fn drop(&mut self) {
+            let start = TEST_JOURNAL.page_offset(0);
+            block_on(with_flash(|flash| {
+                flash.blocking_erase(start, start + BORROWED_BYTES)?;
+                // Blank flash words are left alone, only programmed ones are written back.
+                for (offset, word) in (start..).step_by(8).zip(self.0.chunks_exact(8)) {
+                    if word.iter().any(|byte| *byte != 0xFF) {
+                        flash.blocking_write(offset, word)?;
+                    }
+                }
+                Ok::<_, StorageError>(())
+            }))
+            .expect("test journal pages should be restored");
+        }
+    }
+
+    /// Writes the first flash word of a record, as left by a reset during the write.
+    pub fn write_torn_record(page: u32, slot: u32, payload: [u8; 8]) {
+        let offset = TEST_JOURNAL.slot_offset(page, slot);
+        block_on(with_flash(|flash| {
+            flash
+                .blocking_write(offset, &payload)
+                .map_err(StorageError::from)
+        }))
+        .expect("torn record should write");
+    }
+}
+
+/// Unit tests, run on the target with `cargo test`.
+///
+/// The journal tests borrow the payout configuration pages, which are restored once each test
+/// ends. A failing test stops the run before that, the configuration then has to be sent again.
+#[cfg(test)]
+#[defmt_test::tests]
+mod unit_tests {
+    use defmt::{assert, assert_eq};
+    use embassy_futures::block_on;
+    use embassy_time::{Duration, Instant};
+
+    use crate::{
+        audit::{SavePolicy, AUDIT_SAVE_REQUESTS},
+        config::{ConfigError, ConfigParameter, PayoutConfig, VARIABLE_SET_BYTES},
+        level::{Debouncer, MajorityFilter, MOTOR_NOISE_WINDOW},
+        opto::{ChatterDetector, CHATTER_LIMIT, CHATTER_WINDOW},
+        payout::ends_payout_empty,
+        profile::HOPPER_PROFILE,
+        serial::{parse_serial_code, SerialCodeError},
+        storage::{crc32_update, init_storage, PAGE_SIZE},
+        test_fixtures::{
+            set_variable, variable_set_with, write_torn_record, BorrowedPages, TEST_JOURNAL,
+            TEST_RECORD_SIZE,
+        },
+    };
+
+    #[init]
+    fn init() {
+        let p = embassy_stm32::init(embassy_stm32::Config::default());
+        block_on(init_storage(p.FLASH));
+    }
+
+    #[test]
+    fn audit_saves_do_not_follow_payouts() {
+        const PAYOUTS: u32 = 100;
+        let mut policy = SavePolicy::new();
+        let mut saves = 0;
+        for _ in 0..PAYOUTS {
+            if policy.request() {
+                policy.saved();
+                saves += 1;
+            }
+        }
+        assert_eq!(saves, PAYOUTS / AUDIT_SAVE_REQUESTS);
+        assert!(saves < PAYOUTS);
+    }
+
+    #[test]
+    fn chatter_trips_at_the_limit() {
+        let mut detector = ChatterDetector::new();
+        let start = Instant::from_millis(1_000);
+        for _ in 1..CHATTER_LIMIT {
+            assert!(!detector.record_glitch(start));
+        }
+        assert!(detector.record_glitch(start + CHATTER_WINDOW));
+        // The count starts over once the limit was reported.
+        assert!(!detector.record_glitch(start + CHATTER_WINDOW));
+    }
+
+    #[test]
+    fn chatter_window_expires() {
+        let mut detector = ChatterDetector::new();
+        let start = Instant::from_millis(1_000);
+        for _ in 1..CHATTER_LIMIT {
+            assert!(!detector.record_glitch(start));
+        }
+        let late = start + CHATTER_WINDOW + Duration::from_millis(1);
+        assert!(!detector.record_glitch(late));
+    }
+
+    #[test]
+    fn debounce_reports_at_the_boundary() {
+        let mut debouncer = Debouncer::new(false);
+        let covered_at = Instant::from_millis(1_000);
+        let stable_at = covered_at + HOPPER_PROFILE.level_debounce_covered;
+        assert_eq!(debouncer.sample(true, covered_at), None);
+        assert_eq!(debouncer.deadline(), Some(stable_at));
+        assert_eq!(
+            debouncer.sample(true, stable_at - Duration::from_millis(1)),
+            None
+        );
+        assert_eq!(debouncer.sample(true, stable_at), Some(true));
+        assert_eq!(debouncer.deadline(), None);
+
+        let uncovered_at = stable_at + Duration::from_millis(100);
+        let stable_at = uncovered_at + HOPPER_PROFILE.level_debounce_uncovered;
+        assert_eq!(debouncer.sample(false, uncovered_at), None);
+        assert_eq!(
+            debouncer.sample(false, stable_at - Duration::from_millis(1)),
+            None
+        );
+        assert_eq!(debouncer.sample(false, stable_at), Some(false));
+    }
+
+    #[test]
+    fn debounce_restarts_on_a_bounce() {
+        let mut debouncer = Debouncer::new(false);
+        let start = Instant::from_millis(1_000);
+        assert_eq!(debouncer.sample(true, start), None);
+        assert_eq!(
+            debouncer.sample(false, start + Duration::from_millis(10)),
+            None
+        );
+        assert_eq!(debouncer.deadline(), None);
+
+        let covered_at = start + Duration::from_millis(20);
+        assert_eq!(debouncer.sample(true, covered_at), None);
+        let stable_at = covered_at + HOPPER_PROFILE.level_debounce_covered;
+        assert_eq!(
+            debouncer.sample(true, start + HOPPER_PROFILE.level_debounce_covered),
+            None
+        );
+        assert_eq!(debouncer.sample(true, stable_at), Some(true));
+    }
+
+    #[test]
+    fn majority_needs_more_than_half() {
+        let mut filter = MajorityFilter::new();
+        assert!(filter.push(true));
+        // A tie is not a majority.
+        assert!(!filter.push(false));
+
+        filter.reset();
+        let half = MOTOR_NOISE_WINDOW / 2;
+        for _ in 0..=half {
+            filter.push(false);
+        }
+        for _ in 0..half {
+            assert!(!filter.push(true));
+        }
+        // The window is full, the oldest uncovered sample drops out.
+        assert!(filter.push(true));
+    }
+
+    #[test]
+    fn variable_set_round_trips() {
+        let bytes = HOPPER_PROFILE.payout_config.to_variable_set();
+        assert_eq!(
+            PayoutConfig::from_variable_set(&bytes),
+            Ok(HOPPER_PROFILE.payout_config)
+        );
+    }
+
+    #[test]
+    fn variable_set_accepts_the_range_bounds() {
+        for parameter in ConfigParameter::ALL {
+            for value in <[u16; 2]>::from(parameter.range()) {
+                let bytes = variable_set_with(parameter, value);
+                let config = PayoutConfig::from_variable_set(&bytes);
+                assert_eq!(config.map(|config| config.to_variable_set()), Ok(bytes));
+            }
+        }
+    }
+
+    #[test]
+    fn variable_set_rejects_out_of_range_values() {
+        for parameter in ConfigParameter::ALL {
+            let (min, max) = parameter.range();
+            let below = min
+                .checked_sub(1)
+                .map(|value| variable_set_with(parameter, value));
+            let above = max
+                .checked_add(1)
+                .map(|value| variable_set_with(parameter, value));
+            for bytes in [below, above].into_iter().flatten() {
+                assert_eq!(
+                    PayoutConfig::from_variable_set(&bytes),
+                    Err(ConfigError::OutOfRange(parameter))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn variable_set_rejects_inverted_detection_times() {
+        let mut bytes = variable_set_with(ConfigParameter::MinDetectionTime, 100);
+        set_variable(&mut bytes, ConfigParameter::MaxDetectionTime, 100);
+        assert_eq!(
+            PayoutConfig::from_variable_set(&bytes),
+            Err(ConfigError::DetectionTimesInverted)
+        );
+    }
+
+    #[test]
+    fn variable_set_rejects_the_wrong_length() {
+        let bytes = [0u8; VARIABLE_SET_BYTES + 2];
+        for len in [0, VARIABLE_SET_BYTES - 2, VARIABLE_SET_BYTES + 2] {
+            assert_eq!(
+                PayoutConfig::from_variable_set(&bytes[..len]),
+                Err(ConfigError::InvalidLength)
+            );
+        }
+    }
+
+    #[test]
+    fn crc32_matches_the_check_value() {
+        assert_eq!(!crc32_update(!0, b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn journal_starts_empty() {
+        let _pages = BorrowedPages::erase();
+        let mut payload = [0; 8];
+        assert_eq!(block_on(TEST_JOURNAL.load(&mut payload)), Ok(false));
+    }
+
+    #[test]
+    fn journal_skips_a_torn_record() {
+        let _pages = BorrowedPages::erase();
+        let mut payload = [0; 8];
+        assert_eq!(block_on(TEST_JOURNAL.save(&[1; 8])), Ok(()));
+        write_torn_record(0, 1, [2; 8]);
+        assert_eq!(block_on(TEST_JOURNAL.load(&mut payload)), Ok(true));
+        assert_eq!(payload, [1; 8]);
+
+        // The torn slot is not blank, the next record goes past it.
+        assert_eq!(block_on(TEST_JOURNAL.save(&[3; 8])), Ok(()));
+        assert_eq!(block_on(TEST_JOURNAL.load(&mut payload)), Ok(true));
+        assert_eq!(payload, [3; 8]);
+    }
+
+    #[test]
+    fn journal_survives_a_torn_page_switch() {
+        let _pages = BorrowedPages::erase();
+        let slots = u8::try_from(PAGE_SIZE as usize / TEST_RECORD_SIZE)
+            .expect("a page should hold fewer than 256 records");
+        for sequence in 0..slots {
+            assert_eq!(block_on(TEST_JOURNAL.save(&[sequence; 8])), Ok(()));
+        }
+        // A reset right after the other page was erased for the next record.
+        write_torn_record(1, 0, [0xAA; 8]);
+        let mut payload = [0; 8];
+        assert_eq!(block_on(TEST_JOURNAL.load(&mut payload)), Ok(true));
+        assert_eq!(payload, [slots - 1; 8]);
+
+        assert_eq!(block_on(TEST_JOURNAL.save(&[0x55; 8])), Ok(()));
+        assert_eq!(block_on(TEST_JOURNAL.load(&mut payload)), Ok(true));
+        assert_eq!(payload, [0x55; 8]);
+    }
+
+    #[test]
+    fn serial_code_parses_three_numbers() {
+        assert_eq!(parse_serial_code(None), Ok([0, 215, 0]));
+        assert_eq!(parse_serial_code(Some("1,2,3")), Ok([1, 2, 3]));
+        assert_eq!(parse_serial_code(Some("255,0,007")), Ok([255, 0, 7]));
+    }
+
+    #[test]
+    fn serial_code_rejects_malformed_values() {
+        for value in [
+            "", "1", "1,2", "1,2,", ",1,2", "1,,2", "1,2,3,", "1,2,3,4", " 1,2,3", "1,2,3 ",
+            "-1,2,3", "a,b,c", "1.2.3",
+        ] {
+            assert_eq!(
+                parse_serial_code(Some(value)),
+                Err(SerialCodeError::Malformed)
+            );
+        }
+    }
+
+    #[test]
+    fn serial_code_rejects_numbers_above_255() {
+        for value in ["256,0,0", "0,0,1000", "0,99999,0"] {
+            assert_eq!(
+                parse_serial_code(Some(value)),
+                Err(SerialCodeError::OutOfRange)
+            );
+        }
+    }
+
+    #[test]
+    fn empty_hopper_ends_the_payout_when_enabled() {
+        let config = PayoutConfig {
+            end_payout_when_empty: true,
+            ..HOPPER_PROFILE.payout_config
+        };
+        let timeout = config.empty_no_coin_timeout;
+        assert!(ends_payout_empty(&config, timeout, true));
+        assert!(!ends_payout_empty(&config, timeout, false));
+        assert!(!ends_payout_empty(
+            &config,
+            timeout - Duration::from_millis(1),
+            true
+        ));
+    }
+
+    #[test]
+    fn empty_hopper_keeps_the_payout_when_disabled() {
+        let config = PayoutConfig {
+            end_payout_when_empty: false,
+            ..HOPPER_PROFILE.payout_config
+        };
+        assert!(!ends_payout_empty(
+            &config,
+            config.empty_no_coin_timeout,
+            true
+        ));
+        let bytes = variable_set_with(ConfigParameter::EndPayoutWhenEmpty, 0);
+        assert_eq!(PayoutConfig::from_variable_set(&bytes), Ok(config));
+    }
+}
@@ -1,82 +1,120 @@
 #![no_std]
 #![no_main]
+// The embassy main task holds the non-`Send` `Spawner` across awaits by design.
+#![allow(clippy::future_not_send)]
 
 use cc_talk_core::cc_talk::MAX_BLOCK_LENGTH;
 use cc_talk_device::device_impl::DeviceImpl;
 use cc_talk_device::payout_device::PayoutDevice;
-use defmt::{error, info};
+use defmt::{debug, error, info};
 use embassy_executor::Spawner;
-use embassy_stm32::exti::ExtiInput;
-use embassy_stm32::gpio::{Input, Level, Output, Pull, Speed};
-use embassy_stm32::usart::{Config as UartConfig, DataBits, Parity, StopBits, Uart};
-use embassy_stm32::{bind_interrupts, peripherals, usart, Config};
-use embedded_io_async::Write;
-use universal_hopper_adapter::hopper::*;
+use embassy_futures::select::{select, Either};
+use embassy_stm32::gpio::Level;
+use embassy_stm32::mode::Async;
+use embassy_stm32::usart::Uart;
+use universal_hopper_adapter::audit::audit_task;
+use universal_hopper_adapter::board::{init_board, Board};
+use universal_hopper_adapter::build_info;
+use universal_hopper_adapter::button::user_button_task;
+use universal_hopper_adapter::cctalk;
+use universal_hopper_adapter::clocks::chip_config;
+use universal_hopper_adapter::config::load_payout_config;
+#[cfg(feature = "bootloader")]
+use universal_hopper_adapter::firmware;
+use universal_hopper_adapter::hopper::{set_bus_address, Hopper};
+use universal_hopper_adapter::led::{comms_activity, status_led_task};
 use universal_hopper_adapter::payout::init_payout_tasks;
-use universal_hopper_adapter::reset::{reset_task, send_reset_signal, ResetType};
+#[cfg(feature = "low-power")]
+use universal_hopper_adapter::power::init_low_power;
+use universal_hopper_adapter::power::{bus_idle_timeout, stop_allowed};
+use universal_hopper_adapter::profile::HOPPER_PROFILE;
+use universal_hopper_adapter::reset::reset_task;
+use universal_hopper_adapter::storage::init_storage;
+use universal_hopper_adapter::watchdog::{init_watchdog, supervised, SupervisedTask};
 use {defmt_rtt as _, panic_probe as _};
 
-bind_interrupts!(struct Irqs {
-    USART1 => usart::InterruptHandler<peripherals::USART1>;
-});
-
+#[cfg(not(feature = "low-power"))]
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
-    let config = Config::default();
-
-    let p = embassy_stm32::init(config);
-
-    // In pins
-    let in_1_pin = Output::new(p.PA0, Level::High, Speed::Low);
-    let in_2_pin = Output::new(p.PA1, Level::High, Speed::Low);
-    let in_3_pin = Output::new(p.PA4, Level::Low, Speed::Low);
+    run(spawner).await;
+}
 
-    // Feedback pins
-    let high_level_sensor = ExtiInput::new(p.PA8, p.EXTI8, Pull::Up);
-    let low_level_sensor = ExtiInput::new(p.PB2, p.EXTI2, Pull::Up);
-    let exit_sensor = ExtiInput::new(p.PC6, p.EXTI6, Pull::Up);
+// The low-power executor has no entry macro, it runs the main task itself.
+#[cfg(feature = "low-power")]
+#[cortex_m_rt::entry]
+fn main() -> ! {
+    embassy_stm32::low_power::Executor::take().run(|spawner| {
+        spawner
+            .spawn(main_task(spawner))
+            .expect("main task should run");
+    });
+}
 
-    // Security output
-    let security_output = ExtiInput::new(p.PA9, p.EXTI9, Pull::Up);
+#[cfg(feature = "low-power")]
+#[embassy_executor::task]
+async fn main_task(spawner: Spawner) {
+    run(spawner).await;
+}
 
-    // Address dip switches
-    let addr_1 = Input::new(p.PB3, Pull::None);
-    let addr_2 = Input::new(p.PB4, Pull::None);
-    let addr_3 = Input::new(p.PB5, Pull::None);
+async fn run(spawner: Spawner) {
+    let p = embassy_stm32::init(chip_config());
+    let Board {
+        in_1,
+        in_2,
+        in_3,
+        exit_sensor,
+        low_level_sensor,
+        high_level_sensor,
+        security_output,
+        user_button,
+        status_led,
+        comms_led,
+        address_switches: [addr_1, addr_2, addr_3],
+        mut cctalk,
+        flash,
+        iwdg,
+        rtc,
+    } = init_board(p);
+    init_watchdog(spawner, iwdg);
+    #[cfg(feature = "low-power")]
+    init_low_power(rtc);
+    #[cfg(not(feature = "low-power"))]
+    let _ = rtc;
+    build_info::log();
+    info!("Hopper profile: {}", HOPPER_PROFILE.name);
 
     let address = compute_bus_address(addr_1.get_level(), addr_2.get_level(), addr_3.get_level());
     info!("Hopper address: {}", address);
     set_bus_address(address).await;
 
-    spawner.spawn(reset_task(in_1_pin, in_2_pin)).unwrap();
+    init_storage(flash).await;
+    #[cfg(feature = "bootloader")]
+    {
+        firmware::check_boot().await;
+        spawner
+            .spawn(firmware::trial_task())
+            .expect("trial task should run");
+    }
+    load_payout_config().await;
+    spawner.spawn(audit_task()).expect("audit task should run");
+    spawner
+        .spawn(reset_task(in_1, in_2))
+        .expect("reset task should run");
+    spawner
+        .spawn(status_led_task(status_led, comms_led))
+        .expect("status led task should run");
+    spawner
+        .spawn(user_button_task(user_button))
+        .expect("user button task should run");
     init_payout_tasks(
         spawner,
-        in_3_pin,
+        in_3,
         exit_sensor,
         low_level_sensor,
         high_level_sensor,
         security_output,
     );
 
-    let uart_config = {
-        let mut conf = UartConfig::default();
-        conf.baudrate = 9600;
-        conf.data_bits = DataBits::DataBits8;
-        conf.stop_bits = StopBits::STOP1;
-        conf.parity = Parity::ParityNone;
-        conf
-    };
-    let mut uart = Uart::new_half_duplex(
-        p.USART1,
-        p.PB6,
-        Irqs,
-        p.DMA1_CH1,
-        p.DMA1_CH2,
-        uart_config,
-        usart::HalfDuplexReadback::NoReadback,
-    )
-    .unwrap();
-
     info!("initializing ccTalk buffers");
     let implementation = Hopper;
     info!("ccTalk address: {}", implementation.address());
@@ -84,36 +122,74 @@ async fn main(spawner: Spawner) {
     let mut read_buffer = [0u8; MAX_BLOCK_LENGTH];
     let mut reply_buffer = [0u8; MAX_BLOCK_LENGTH];
     loop {
-        match uart.read_until_idle(&mut read_buffer).await {
-            Ok(len) => {
-                if len == 0 {
-                    continue; // Don't waste processing time on empty reads
+        let mut uart = cctalk.uart();
+        serve_bus(&mut uart, &device, &mut read_buffer, &mut reply_buffer).await;
+        drop(uart);
+
+        info!("ccTalk bus quiet, releasing the uart");
+        supervised(SupervisedTask::CcTalk, cctalk.wait_for_activity()).await;
+    }
+}
+
+/// Answers ccTalk frames until the bus stays quiet long enough for the chip to idle in STOP mode.
+async fn serve_bus(
+    uart: &mut Uart<'_, Async>,
+    device: &PayoutDevice<Hopper>,
+    read_buffer: &mut [u8],
+    reply_buffer: &mut [u8],
+) {
+    loop {
+        let len = match supervised(
+            SupervisedTask::CcTalk,
+            select(uart.read_until_idle(read_buffer), bus_idle_timeout()),
+        )
+        .await
+        {
+            Either::First(Ok(len)) => len,
+            Either::First(Err(_)) => {
+                error!("Error processing frame");
+                continue;
+            }
+            Either::Second(()) => {
+                if stop_allowed().await {
+                    return;
                 }
+                continue;
+            }
+        };
 
-                match device
-                    .on_frame(&mut read_buffer[..len], reply_buffer.as_mut_slice())
-                    .await
-                {
-                    Ok(reply_len) => {
-                        let result = uart.write(&reply_buffer[..reply_len]).await;
-                        if result.is_err() {
-                            error!("Error writing reply: {:?}", result);
-                        } else {
-                            info!("Reply sent: {:?}", &reply_buffer[..reply_len]);
-                        }
-                    }
-                    Err(error) => {
-                        error!("Error reading packet: {:?} {}", error, read_buffer[..len]);
-                        continue;
+        if len == 0 {
+            continue; // Don't waste processing time on empty reads
+        }
+
+        cctalk::check_address_clash(&mut read_buffer[..len]);
+        let reply = match cctalk::on_frame(&mut read_buffer[..len], reply_buffer).await {
+            Some(reply) => reply,
+            None => device.on_frame(&mut read_buffer[..len], reply_buffer).await,
+        };
+        match reply {
+            Ok(0) => debug!("No reply sent"),
+            Ok(reply_len) => {
+                let result = uart.write(&reply_buffer[..reply_len]).await;
+                if result.is_err() {
+                    error!("Error writing reply: {:?}", result);
+                } else {
+                    comms_activity();
+                    info!("Reply sent: {:?}", &reply_buffer[..reply_len]);
+                    #[cfg(feature = "bootloader")]
+                    {
+                        firmware::confirm_boot().await;
+                        firmware::restart_if_pending().await;
                     }
                 }
             }
-            Err(_) => error!("Error processing frame"),
+            Err(error) => {
+                error!("Error reading packet: {:?} {}", error, read_buffer[..len]);
+            }
         }
     }
 }
 
-#[allow(dead_code)]
 fn compute_bus_address(addr_1: Level, addr_2: Level, addr_3: Level) -> u8 {
     info!(
         "Bus address dip switches: {}, {}, {}",
@@ -132,11 +208,3 @@ fn compute_bus_address(addr_1: Level, addr_2: Level, addr_3: Level) -> u8 {
     }
     address
 }
-
-#[embassy_executor::task]
-async fn reset_hopper(mut user_button: ExtiInput<'static>) {
-    loop {
-        user_button.wait_for_falling_edge().await;
-        send_reset_signal(ResetType::Hopper);
-    }
-}
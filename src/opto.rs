@@ -0,0 +1,139 @@
+use defmt::{info, warn};
+use embassy_stm32::exti::ExtiInput;
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, channel::Channel};
+use embassy_time::{Duration, Instant};
+
+use crate::{
+    config::payout_config,
+    profile::HOPPER_PROFILE,
+    watchdog::{supervised, SupervisedTask},
+};
+
+/// Number of glitches within [`CHATTER_WINDOW`] reported as chatter, a fraud attempt while idle
+/// and a faulty opto during a payout.
+pub(crate) const CHATTER_LIMIT: u8 = 5;
+pub(crate) const CHATTER_WINDOW: Duration = Duration::from_millis(1_000);
+
+/// Edges waiting to be processed, a burst of coins fits without dropping any.
+static OPTO_EDGES: Channel<ThreadModeRawMutex, OptoEdge, 16> = Channel::new();
+
+/// Edge seen on the exit opto, timestamped as soon as the EXTI interrupt woke the capture task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct OptoEdge {
+    /// `true` when the opto became blocked, `false` when it cleared.
+    pub blocked: bool,
+    pub at: Instant,
+}
+
+/// Classification of a single exit opto pulse.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum PulseClass {
+    /// Too short to be a coin, could be noise or an object being jiggled in the exit.
+    Glitch,
+    Coin,
+    /// The opto stayed blocked for longer than any coin would.
+    Blocked,
+}
+
+/// Classifies an exit opto pulse from the time it stayed blocked.
+#[must_use]
+pub fn classify_pulse(width: Duration) -> PulseClass {
+    let config = payout_config();
+    if width < config.min_detection_time {
+        PulseClass::Glitch
+    } else if width <= config.max_detection_time {
+        PulseClass::Coin
+    } else {
+        PulseClass::Blocked
+    }
+}
+
+/// Detects chattering on the exit opto, as seen when something on a string is moved back and
+/// forth through the exit.
+pub struct ChatterDetector {
+    window_start: Instant,
+    glitches: u8,
+}
+
+impl ChatterDetector {
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            window_start: Instant::now(),
+            glitches: 0,
+        }
+    }
+
+    pub const fn reset(&mut self) {
+        self.glitches = 0;
+    }
+
+    /// Records a glitch, returns `true` when the chatter limit is reached.
+    pub fn record_glitch(&mut self, at: Instant) -> bool {
+        if self.glitches == 0 || at - self.window_start > CHATTER_WINDOW {
+            self.window_start = at;
+            self.glitches = 0;
+        }
+
+        self.glitches += 1;
+        if self.glitches >= CHATTER_LIMIT {
+            self.reset();
+            return true;
+        }
+        false
+    }
+}
+
+impl Default for ChatterDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Waits for the next exit opto edge. Edges always alternate between blocked and cleared.
+pub async fn next_edge() -> OptoEdge {
+    OPTO_EDGES.receive().await
+}
+
+/// Waits until the exit opto is cleared.
+pub async fn wait_for_release() {
+    while supervised(SupervisedTask::ExitSensor, next_edge())
+        .await
+        .blocked
+    {}
+}
+
+fn push_edge(edge: OptoEdge) {
+    if OPTO_EDGES.try_send(edge).is_err() {
+        warn!("exit opto edge queue full, dropping {}", edge);
+    }
+}
+
+/// Timestamps the exit opto edges as they are signaled by the EXTI line, so the pulse width and
+/// the spacing between coins do not depend on how fast the edges are processed.
+///
+/// The level of a blocked opto comes from the hopper profile.
+#[embassy_executor::task]
+pub async fn exit_opto_capture_task(mut exit_sensor: ExtiInput<'static>) {
+    info!("exit opto capture task started");
+    let mut blocked = exit_sensor.get_level() == HOPPER_PROFILE.opto_blocked_level;
+    loop {
+        supervised(SupervisedTask::ExitOpto, exit_sensor.wait_for_any_edge()).await;
+        let at = Instant::now();
+        let level_blocked = exit_sensor.get_level() == HOPPER_PROFILE.opto_blocked_level;
+
+        if level_blocked == blocked {
+            // Both edges happened before the task woke up, report a zero width pulse so the
+            // edges keep alternating.
+            push_edge(OptoEdge {
+                blocked: !blocked,
+                at,
+            });
+        }
+        push_edge(OptoEdge {
+            blocked: level_blocked,
+            at,
+        });
+        blocked = level_blocked;
+    }
+}
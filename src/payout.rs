@@ -1,14 +1,26 @@
-use cc_talk_core::cc_talk::{HopperDispenseStatus, HopperStatus};
+use cc_talk_core::cc_talk::{HopperDispenseStatus, HopperFlag};
 use defmt::{debug, info, trace, warn};
 use embassy_executor::Spawner;
-use embassy_futures::select::{select, Either};
-use embassy_stm32::{
-    exti::ExtiInput,
-    gpio::{Level, Output},
-};
+use embassy_futures::select::{select, select3, Either, Either3};
+use embassy_stm32::{exti::ExtiInput, gpio::Output};
 use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, mutex::Mutex, signal::Signal};
 use embassy_time::{Duration, Instant, Timer};
-use embedded_hal::digital::InputPin;
+
+use crate::{
+    audit::{add_motor_runtime, audit_counters, increment, request_audit_save, AuditCounter},
+    config::{payout_config, PayoutConfig},
+    events::{log_event, Event},
+    faults::{payout_inhibited, raise_flag, record_fault, Fault},
+    fill::coins_dispensed,
+    level::{is_below_low_level, sensor_task, set_motor_running},
+    opto::{
+        classify_pulse, exit_opto_capture_task, next_edge, wait_for_release, ChatterDetector,
+        PulseClass,
+    },
+    profile::HOPPER_PROFILE,
+    refill::refill_in_progress,
+    watchdog::{supervised, SupervisedTask},
+};
 
 static PAYOUT_SIGNAL: Signal<ThreadModeRawMutex, u8> = Signal::new();
 static ENABLE_PAYOUT_SIGNAL: Signal<ThreadModeRawMutex, bool> = Signal::new();
@@ -21,7 +33,6 @@ enum MotorCommand {
     Stop,
 }
 static CHANGE_MOTOR_STATE_SIGNAL: Signal<ThreadModeRawMutex, MotorCommand> = Signal::new();
-static SENSOR_STATE_SIGNAL: Signal<ThreadModeRawMutex, bool> = Signal::new();
 
 static CURRENT_PAYOUT_STATUS: Mutex<ThreadModeRawMutex, HopperDispenseStatus> =
     Mutex::new(HopperDispenseStatus {
@@ -31,11 +42,34 @@ static CURRENT_PAYOUT_STATUS: Mutex<ThreadModeRawMutex, HopperDispenseStatus> =
         unpaid: 0,
     });
 
-static HIGH_LEVEL_SENSOR: Mutex<ThreadModeRawMutex, Level> = Mutex::new(Level::Low);
-static LOW_LEVEL_SENSOR: Mutex<ThreadModeRawMutex, Level> = Mutex::new(Level::Low);
+/// Mirror of the payout task enable state, used by the motor safety interlock.
+static PAYOUT_ENABLED: Mutex<ThreadModeRawMutex, bool> = Mutex::new(false);
+/// Whether IN3 is currently driving the motor.
+static MOTOR_RUNNING: Mutex<ThreadModeRawMutex, bool> = Mutex::new(false);
 
 /// Hopper dispense count since last reset or power on.
 static DISPENSE_COUNT: Mutex<ThreadModeRawMutex, u32> = Mutex::new(0);
+/// Coins seen on the exit sensor after the motor was stopped, since last reset or power on.
+static OVERPAID_COUNT: Mutex<ThreadModeRawMutex, u32> = Mutex::new(0);
+/// Overpaid coins not yet deducted from a payout, only used with overpay compensation enabled in
+/// the payout configuration.
+static OVERPAY_CREDIT: Mutex<ThreadModeRawMutex, u8> = Mutex::new(0);
+/// Time at which the last coin was counted on the exit opto.
+static LAST_COIN_AT: Mutex<ThreadModeRawMutex, Option<Instant>> = Mutex::new(None);
+/// Timing of the last coin seen on the exit opto.
+static LAST_COIN_TIMING: Mutex<ThreadModeRawMutex, Option<CoinTiming>> = Mutex::new(None);
+/// Whether the security output of the hopper is at its tripped level.
+static SECURITY_TRIPPED: Mutex<ThreadModeRawMutex, bool> = Mutex::new(false);
+
+/// Exit opto timing of a paid out coin.
+#[derive(Clone, Copy, Debug, defmt::Format, Eq, PartialEq)]
+pub struct CoinTiming {
+    /// How long the coin blocked the exit opto.
+    pub width: Duration,
+    /// Time between the previous coin clearing the opto and this one blocking it, `None` for
+    /// the first coin of a payout.
+    pub gap: Option<Duration>,
+}
 
 /// Initializes the payout tasks
 ///
@@ -56,7 +90,10 @@ pub fn init_payout_tasks(
         .spawn(sensor_task(low_level_sensor, high_level_sensor))
         .expect("sensor task should run");
     spawner
-        .spawn(exit_sensor_task(exit_sensor))
+        .spawn(exit_opto_capture_task(exit_sensor))
+        .expect("exit opto capture task should run");
+    spawner
+        .spawn(exit_sensor_task())
         .expect("exit sensor task should run");
     spawner
         .spawn(payout_task())
@@ -77,6 +114,32 @@ pub async fn get_dispense_count() -> u32 {
     *count
 }
 
+pub async fn get_overpaid_count() -> u32 {
+    let count = OVERPAID_COUNT.lock().await;
+    *count
+}
+
+pub async fn get_last_coin_timing() -> Option<CoinTiming> {
+    let timing = LAST_COIN_TIMING.lock().await;
+    *timing
+}
+
+/// Whether the host enabled payouts.
+pub async fn is_payout_enabled() -> bool {
+    *PAYOUT_ENABLED.lock().await
+}
+
+/// Whether the motor is currently driven.
+pub async fn is_motor_running() -> bool {
+    *MOTOR_RUNNING.lock().await
+}
+
+/// Whether the security output of the hopper is currently tripped.
+pub async fn is_security_tripped() -> bool {
+    *SECURITY_TRIPPED.lock().await
+}
+
+/// Cuts the motor right away (header 172), the coins still to pay are marked unpaid.
 pub fn emergency_stop() {
     EMERGENCY_STOP_SIGNAL.signal(());
 }
@@ -94,20 +157,6 @@ pub fn request_payout(count: u8) {
     PAYOUT_SIGNAL.signal(count);
 }
 
-pub async fn get_sensor_status() -> HopperStatus {
-    let high: Level;
-    let low: Level;
-    {
-        let high_level = HIGH_LEVEL_SENSOR.lock().await;
-        high = *high_level;
-    };
-    {
-        let low_level = LOW_LEVEL_SENSOR.lock().await;
-        low = *low_level;
-    };
-    HopperStatus::new(true, low == Level::Low, true, high == Level::Low)
-}
-
 #[embassy_executor::task]
 async fn payout_task() {
     info!("payout task started");
@@ -119,9 +168,15 @@ async fn payout_task() {
             EMERGENCY_STOP_SIGNAL.reset();
         }
 
-        match select(ENABLE_PAYOUT_SIGNAL.wait(), PAYOUT_SIGNAL.wait()).await {
+        match supervised(
+            SupervisedTask::Payout,
+            select(ENABLE_PAYOUT_SIGNAL.wait(), PAYOUT_SIGNAL.wait()),
+        )
+        .await
+        {
             Either::First(enable) => {
                 payout_enabled = enable;
+                *PAYOUT_ENABLED.lock().await = enable;
                 info!("payout enabled status: {}", payout_enabled);
             }
             Either::Second(count) => {
@@ -130,33 +185,140 @@ async fn payout_task() {
                     continue;
                 }
 
+                if payout_inhibited() {
+                    warn!("Payout signal received but payouts are inhibited by a fraud fault");
+                    continue;
+                }
+
+                if refill_in_progress() {
+                    warn!("Payout signal received during a refill");
+                    continue;
+                }
+
+                let compensated = take_overpay_credit(count).await;
                 {
                     let mut event = CURRENT_PAYOUT_STATUS.lock().await;
                     *event = event.payout_requested(count);
+                    if compensated > 0 {
+                        info!("{} coins already overpaid, deducting them", compensated);
+                        *event = event.coin_paid(compensated);
+                    }
+                    if event.coins_remaining == 0 {
+                        continue;
+                    }
                 }
 
+                increment(AuditCounter::Payouts, 1);
                 CHANGE_MOTOR_STATE_SIGNAL.signal(MotorCommand::Start);
             }
         }
     }
 }
 
-const MIN_BRAKE_TIME_MS: u64 = 50;
-const MIN_BRAKE_TIME: Duration = Duration::from_millis(MIN_BRAKE_TIME_MS);
+// Motor safety interlock constants
+const MOTOR_SAFETY_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+const MOTOR_SPIN_UP_ALLOWANCE: Duration = Duration::from_secs(2);
+
+/// Hard limit on how long the motor may run for a payout of `coins`, independent of the coin
+/// counting done by the exit sensor and the bookkeeper.
+fn max_motor_on_time(coins: u32) -> Duration {
+    MOTOR_SPIN_UP_ALLOWANCE + payout_config().motor_max_time_per_coin * coins
+}
+
+/// Coins paid out since the controller started, as counted by the audit meters.
+fn coins_paid() -> u32 {
+    audit_counters()[AuditCounter::CoinsPaid as usize]
+}
+
+/// Whether a payout is ended early with the hopper empty, after `idle` without coins on the exit
+/// opto. The low level plate and the exit opto have to agree: a few coins can still be paid out
+/// once the plate is uncovered.
+pub(crate) fn ends_payout_empty(
+    config: &PayoutConfig,
+    idle: Duration,
+    below_low_level: bool,
+) -> bool {
+    config.end_payout_when_empty && idle >= config.empty_no_coin_timeout && below_low_level
+}
+
+/// Whether the running payout is ended early with the hopper empty, see [`ends_payout_empty`].
+async fn is_hopper_empty(motor_started_at: Instant) -> bool {
+    let last_activity = LAST_COIN_AT
+        .lock()
+        .await
+        .map_or(motor_started_at, |last_coin| {
+            last_coin.max(motor_started_at)
+        });
+    ends_payout_empty(
+        &payout_config(),
+        last_activity.elapsed(),
+        is_below_low_level().await,
+    )
+}
+
+/// Cuts the motor and tells the tasks depending on its state, returns the stop time.
+async fn cut_motor(in_3: &mut Output<'static>, started_at: Instant) -> Instant {
+    in_3.set_level(HOPPER_PROFILE.motor_off_level());
+    let was_running = core::mem::replace(&mut *MOTOR_RUNNING.lock().await, false);
+    set_motor_running(false);
+
+    let now = Instant::now();
+    if was_running {
+        add_motor_runtime(now - started_at);
+        request_audit_save();
+    }
+    now
+}
+
+/// Marks the coins still to pay as unpaid, returns how many there were.
+async fn mark_remaining_unpaid() -> u8 {
+    let unpaid = {
+        let mut event = CURRENT_PAYOUT_STATUS.lock().await;
+        let unpaid = event.coins_remaining;
+        *event = event.coin_unpaid(unpaid);
+        unpaid
+    };
+    increment(AuditCounter::CoinsUnpaid, u32::from(unpaid));
+    unpaid
+}
+
 #[embassy_executor::task]
 async fn motor_control_task(mut in_3: Output<'static>) {
     let mut last_stop_time = Instant::now();
+    let mut motor_started_at = Instant::now();
+    let mut paid_at_start = 0;
+    let mut motor_deadline: Option<Instant> = None;
     loop {
-        match select(
-            CHANGE_MOTOR_STATE_SIGNAL.wait(),
-            EMERGENCY_STOP_SIGNAL.wait(),
+        let safety_check = async {
+            match motor_deadline {
+                Some(_) => Timer::after(MOTOR_SAFETY_CHECK_INTERVAL).await,
+                None => core::future::pending().await,
+            }
+        };
+
+        match supervised(
+            SupervisedTask::MotorControl,
+            select3(
+                CHANGE_MOTOR_STATE_SIGNAL.wait(),
+                EMERGENCY_STOP_SIGNAL.wait(),
+                safety_check,
+            ),
         )
         .await
         {
-            Either::First(command) => match command {
+            Either3::First(command) => match command {
+                MotorCommand::Start if motor_deadline.is_some() => {
+                    // More coins for the running payout: the motor keeps its start time, so the
+                    // interlock only grows by the time allowed for the coins added.
+                    let remaining = u32::from(get_payout_status().await.coins_remaining);
+                    let paid = coins_paid().saturating_sub(paid_at_start);
+                    motor_deadline = Some(motor_started_at + max_motor_on_time(paid + remaining));
+                    info!("motor command: start, motor already running");
+                }
                 MotorCommand::Start => {
-                    if (Instant::now() - last_stop_time) < MIN_BRAKE_TIME {
-                        let wait_time = MIN_BRAKE_TIME - (Instant::now() - last_stop_time);
+                    let min_brake_time = payout_config().min_brake_time;
+                    if (Instant::now() - last_stop_time) < min_brake_time {
+                        let wait_time = min_brake_time - (Instant::now() - last_stop_time);
                         info!(
                             "motor start command received too soon after stop, waiting {:?}",
                             wait_time
@@ -164,80 +326,268 @@ async fn motor_control_task(mut in_3: Output<'static>) {
                         Timer::after(wait_time).await;
                     }
 
+                    let coins = get_payout_status().await.coins_remaining;
+                    motor_started_at = Instant::now();
+                    paid_at_start = coins_paid();
+                    motor_deadline = Some(motor_started_at + max_motor_on_time(u32::from(coins)));
+
                     info!("motor command: start");
-                    in_3.set_high();
-                    SENSOR_STATE_SIGNAL.signal(false);
+                    in_3.set_level(HOPPER_PROFILE.motor_on_level);
+                    *MOTOR_RUNNING.lock().await = true;
+                    set_motor_running(true);
                     EXIT_SENSOR_SIGNAL.signal(());
                 }
                 MotorCommand::Stop => {
                     info!("motor command: stop");
-                    in_3.set_low();
-                    last_stop_time = Instant::now();
-                    SENSOR_STATE_SIGNAL.signal(true);
+                    last_stop_time = cut_motor(&mut in_3, motor_started_at).await;
+                    motor_deadline = None;
                 }
             },
-            Either::Second(()) => {
+            Either3::Second(()) => {
                 warn!("emergency stop triggered, stopping motor");
-                in_3.set_low();
-                last_stop_time = Instant::now();
-                SENSOR_STATE_SIGNAL.signal(true);
+                increment(AuditCounter::EmergencyStops, 1);
+                last_stop_time = cut_motor(&mut in_3, motor_started_at).await;
+                motor_deadline = None;
+                mark_remaining_unpaid().await;
+            }
+            Either3::Third(()) => {
+                let fault = if !*PAYOUT_ENABLED.lock().await {
+                    Some(Fault::MotorRunningWhilePayoutDisabled)
+                } else if motor_deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    Some(Fault::MotorRunTimeExceeded)
+                } else {
+                    None
+                };
+
+                if let Some(fault) = fault {
+                    warn!("motor safety interlock fired, stopping motor");
+                    last_stop_time = cut_motor(&mut in_3, motor_started_at).await;
+                    motor_deadline = None;
+                    record_fault(fault);
+                    mark_remaining_unpaid().await;
+                } else if is_hopper_empty(motor_started_at).await {
+                    warn!("hopper empty, ending payout");
+                    last_stop_time = cut_motor(&mut in_3, motor_started_at).await;
+                    motor_deadline = None;
+                    raise_flag(HopperFlag::PayoutTimeoutOccurred);
+
+                    let unpaid = mark_remaining_unpaid().await;
+                    log_event(Event::PayoutEndedEmpty { unpaid });
+                }
             }
         };
     }
 }
 
-// Exit sensor constants
-const BUSY_LOOP_DELAY: Duration = Duration::from_millis(1);
-const MIN_DETECTION_TIME: Duration = Duration::from_millis(30);
+/// Counts the coins going through the exit opto from the timestamped edges of
+/// [`exit_opto_capture_task`], and checks every pulse for fraud patterns and opto faults.
 #[embassy_executor::task]
-async fn exit_sensor_task(mut exit_sensor: ExtiInput<'static>) {
+async fn exit_sensor_task() {
     let mut is_in_payout = false;
-    let mut detection_time;
+    // Kept apart, so glitches seen while paying out are not taken for fraud once the motor stops.
+    let mut idle_chatter = ChatterDetector::new();
+    let mut payout_chatter = ChatterDetector::new();
+    let mut last_release: Option<Instant> = None;
     loop {
         if !is_in_payout {
-            EXIT_SENSOR_SIGNAL.wait().await;
-            is_in_payout = true;
-            while exit_sensor.is_high() {
-                Timer::after(Duration::from_millis(1)).await;
+            match supervised(
+                SupervisedTask::ExitSensor,
+                select(EXIT_SENSOR_SIGNAL.wait(), next_edge()),
+            )
+            .await
+            {
+                Either::First(()) => {
+                    is_in_payout = true;
+                    payout_chatter.reset();
+                    last_release = None;
+                }
+                Either::Second(edge) => {
+                    if edge.blocked {
+                        check_idle_pulse(edge.at, &mut idle_chatter).await;
+                    }
+                }
             }
+            continue;
         }
 
-        exit_sensor.wait_for_low().await;
-        detection_time = Instant::now();
+        let edge = supervised(SupervisedTask::ExitSensor, next_edge()).await;
+        if !edge.blocked {
+            continue;
+        }
 
-        loop {
-            if exit_sensor.is_high() {
-                break;
-            }
+        // The motor was stopped by someone else (bookkeeper, interlock, emergency stop).
+        if !*MOTOR_RUNNING.lock().await {
+            is_in_payout = false;
+            check_idle_pulse(edge.at, &mut idle_chatter).await;
+            continue;
+        }
 
-            if detection_time.elapsed() >= MIN_DETECTION_TIME {
-                {
-                    let mut event = CURRENT_PAYOUT_STATUS.lock().await;
-                    *event = event.coin_paid(1);
-                    debug!("coins remaining: {}", event.coins_remaining);
+        let config = payout_config();
+        if let Either::First(release) =
+            select(next_edge(), Timer::at(edge.at + config.min_detection_time)).await
+        {
+            trace!("exit opto glitch of {:?}", release.at - edge.at);
+            if payout_chatter.record_glitch(release.at) {
+                is_in_payout = false;
+                abort_payout(Fault::OptoChatterDuringPayout).await;
+            }
+            continue;
+        }
 
-                    if event.coins_remaining == 0 {
-                        is_in_payout = false;
-                        CHANGE_MOTOR_STATE_SIGNAL.signal(MotorCommand::Stop);
-                    }
+        {
+            *LAST_COIN_AT.lock().await = Some(Instant::now());
+            let mut event = CURRENT_PAYOUT_STATUS.lock().await;
+            *event = event.coin_paid(1);
+            debug!("coins remaining: {}", event.coins_remaining);
+
+            if event.coins_remaining == 0 {
+                is_in_payout = false;
+                CHANGE_MOTOR_STATE_SIGNAL.signal(MotorCommand::Stop);
+            }
 
-                    drop(event);
+            drop(event);
 
-                    let mut dispense_count = DISPENSE_COUNT.lock().await;
-                    *dispense_count = dispense_count.wrapping_add(1);
+            let mut dispense_count = DISPENSE_COUNT.lock().await;
+            *dispense_count = dispense_count.wrapping_add(1);
+        }
+        coins_dispensed(1);
+        increment(AuditCounter::CoinsPaid, 1);
+
+        match select(next_edge(), Timer::at(edge.at + config.max_detection_time)).await {
+            Either::First(release) => {
+                let timing = CoinTiming {
+                    width: release.at - edge.at,
+                    gap: last_release.map(|last| edge.at - last),
+                };
+                trace!("coin timing: {}", timing);
+                *LAST_COIN_TIMING.lock().await = Some(timing);
+                last_release = Some(release.at);
+
+                if !is_in_payout && monitor_overpay().await {
+                    is_in_payout = true;
+                    payout_chatter.reset();
+                    last_release = None;
+                }
+            }
+            Either::Second(()) => {
+                warn!("exit opto blocked during payout");
+                if is_in_payout {
+                    is_in_payout = false;
+                    abort_payout(Fault::OptoBlockedDuringPayout).await;
+                } else {
+                    record_fault(Fault::OptoBlockedDuringPayout);
                 }
+                wait_for_release().await;
+                info!("exit opto jam cleared");
+                increment(AuditCounter::JamsCleared, 1);
+            }
+        }
+    }
+}
 
-                exit_sensor.wait_for_high().await;
-                break;
+/// Classifies a pulse that started at `start` while the motor is off. Chatter and a sustained
+/// block are reported as fraud attempts, a coin length pulse is only recorded as it is most
+/// likely a coin dropping late.
+async fn check_idle_pulse(start: Instant, chatter: &mut ChatterDetector) {
+    let max_detection_time = payout_config().max_detection_time;
+    let class = match select(next_edge(), Timer::at(start + max_detection_time)).await {
+        Either::First(release) => classify_pulse(release.at - start),
+        Either::Second(()) => PulseClass::Blocked,
+    };
+    debug!("idle exit opto pulse: {}", class);
+
+    match class {
+        PulseClass::Glitch => {
+            if chatter.record_glitch(Instant::now()) {
+                record_fault(Fault::OptoChatterDuringIdle);
             }
+        }
+        PulseClass::Coin => record_fault(Fault::CoinDuringIdle),
+        PulseClass::Blocked => {
+            record_fault(Fault::OptoBlockedDuringIdle);
+            wait_for_release().await;
+        }
+    }
+}
+
+/// Stops the motor and marks the remaining coins as unpaid after an exit opto fault.
+async fn abort_payout(fault: Fault) {
+    warn!("aborting payout: {}", fault);
+    record_fault(fault);
+    CHANGE_MOTOR_STATE_SIGNAL.signal(MotorCommand::Stop);
+    mark_remaining_unpaid().await;
+}
 
-            Timer::after(BUSY_LOOP_DELAY).await;
+/// Watches the exit sensor for the overpay window after the motor was stopped, coins dropping
+/// through during run-down are counted as dispensed and logged as overpaid. The window ends early
+/// when the next payout starts the motor, returning `true` so its coins are counted as paid.
+async fn monitor_overpay() -> bool {
+    let window_end = Instant::now() + payout_config().overpay_window;
+    let mut overpaid: u8 = 0;
+
+    let payout_started = loop {
+        let edge = match supervised(
+            SupervisedTask::ExitSensor,
+            select3(
+                next_edge(),
+                Timer::at(window_end),
+                EXIT_SENSOR_SIGNAL.wait(),
+            ),
+        )
+        .await
+        {
+            Either3::First(edge) => edge,
+            Either3::Second(()) => break false,
+            Either3::Third(()) => break true,
+        };
+        if !edge.blocked {
+            continue;
+        }
+
+        // Shorter pulses are noise, the same as during payout.
+        let min_detection_time = payout_config().min_detection_time;
+        match select(next_edge(), Timer::at(edge.at + min_detection_time)).await {
+            Either::First(_) => {}
+            Either::Second(()) => {
+                overpaid = overpaid.saturating_add(1);
+                wait_for_release().await;
+            }
         }
+    };
+
+    if overpaid > 0 {
+        record_overpay(overpaid).await;
+    }
+    payout_started
+}
+
+async fn record_overpay(overpaid: u8) {
+    warn!("{} coins overpaid after motor stop", overpaid);
+    log_event(Event::Overpaid { coins: overpaid });
+    {
+        let mut dispense_count = DISPENSE_COUNT.lock().await;
+        *dispense_count = dispense_count.wrapping_add(u32::from(overpaid));
+    }
+    coins_dispensed(u16::from(overpaid));
+    increment(AuditCounter::CoinsPaid, u32::from(overpaid));
+    {
+        let mut overpaid_count = OVERPAID_COUNT.lock().await;
+        *overpaid_count = overpaid_count.wrapping_add(u32::from(overpaid));
+    }
+    if payout_config().overpay_compensation {
+        let mut credit = OVERPAY_CREDIT.lock().await;
+        *credit = credit.saturating_add(overpaid);
     }
 }
 
-const BK_MAX_TRIES: u8 = 2;
-const BK_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Takes up to `count` coins from the overpay credit.
+async fn take_overpay_credit(count: u8) -> u8 {
+    let mut credit = OVERPAY_CREDIT.lock().await;
+    let taken = (*credit).min(count);
+    *credit -= taken;
+    taken
+}
+
 /// Makes sure the payout status is updated periodically, and resets it if no changes are detected
 /// for a certain number of tries. It will mark the coins as unpaid
 #[embassy_executor::task]
@@ -247,7 +597,12 @@ async fn book_keeper_task() {
     let mut tries = 0;
     loop {
         // This task can be used to log or process the payout status periodically
-        Timer::after(BK_POLL_INTERVAL).await;
+        let config = payout_config();
+        supervised(
+            SupervisedTask::BookKeeper,
+            Timer::after(config.book_keeper_poll_interval),
+        )
+        .await;
         let status = get_payout_status().await;
         if status.coins_remaining != 0 && last_remaining == 0 {
             last_remaining = status.coins_remaining;
@@ -269,15 +624,12 @@ async fn book_keeper_task() {
             last_remaining = status.coins_remaining;
         }
 
-        if tries >= BK_MAX_TRIES {
+        if tries >= config.book_keeper_max_tries {
             warn!(
                 "Bookkeeper: No change in coins remaining for {} tries, resetting payout status",
-                BK_MAX_TRIES
+                tries
             );
-            {
-                let mut event = CURRENT_PAYOUT_STATUS.lock().await;
-                *event = event.coin_unpaid(event.coins_remaining);
-            };
+            mark_remaining_unpaid().await;
             last_remaining = 0;
             tries = 0;
 
@@ -291,58 +643,14 @@ async fn book_keeper_task() {
 async fn security_output_task(mut security_output: ExtiInput<'static>) {
     info!("security output task started");
     loop {
-        security_output.wait_for_falling_edge().await;
-    }
-}
-
-// Constants for sensor polling
-const SENSOR_POLLING_INTERVAL: Duration = Duration::from_secs(5);
-#[embassy_executor::task]
-async fn sensor_task(low_level_sensor: ExtiInput<'static>, high_level_sensor: ExtiInput<'static>) {
-    info!("sensor task started");
-
-    let low_level_sensor_level = low_level_sensor.get_level();
-    let high_level_sensor_level = high_level_sensor.get_level();
-    let mut enabled = true;
-
-    info!(
-        "polling initial sensor levels, low sensor {}, high sensor {}",
-        low_level_sensor_level, high_level_sensor_level
-    );
-
-    {
-        let mut lll = LOW_LEVEL_SENSOR.lock().await;
-        *lll = low_level_sensor_level;
-    };
-    {
-        let mut hll = HIGH_LEVEL_SENSOR.lock().await;
-        *hll = high_level_sensor_level;
-    };
-
-    info!("initial sensor levels set, starting event loop");
-    loop {
-        Timer::after(SENSOR_POLLING_INTERVAL).await;
-
-        if SENSOR_STATE_SIGNAL.signaled() {
-            enabled = SENSOR_STATE_SIGNAL.wait().await;
-        }
-
-        if !enabled {
-            trace!("sensor are disabled, skipping sensor polling");
-            continue;
-        }
-
-        let level = low_level_sensor.get_level();
-        {
-            debug!("low level sensor: {}", level);
-            let mut lll = LOW_LEVEL_SENSOR.lock().await;
-            *lll = level;
-        }
-        let level = high_level_sensor.get_level();
-        {
-            debug!("high level sensor: {}", level);
-            let mut hll = HIGH_LEVEL_SENSOR.lock().await;
-            *hll = level;
+        let tripped = security_output.get_level() == HOPPER_PROFILE.security_tripped_level;
+        let was_tripped = core::mem::replace(&mut *SECURITY_TRIPPED.lock().await, tripped);
+        if tripped && !was_tripped {
+            warn!("hopper security output tripped");
+            increment(AuditCounter::SecurityTrips, 1);
+        } else if !tripped && was_tripped {
+            info!("hopper security output cleared");
         }
+        security_output.wait_for_any_edge().await;
     }
 }
@@ -0,0 +1,159 @@
+use defmt::{error, info, warn};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, mutex::Mutex};
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::{
+    events::{log_event, Event},
+    storage::{Journal, StorageError, RECORD_OVERHEAD},
+};
+
+/// Delay before acknowledging a wrong PIN, makes exhaustive searching slower.
+const PIN_FAILURE_DELAY: Duration = Duration::from_millis(500);
+/// Wrong PINs accepted before PIN entry is locked out.
+const PIN_MAX_FAILURES: u8 = 5;
+/// Time during which every PIN entry is ignored after too many failures. A lockout still running
+/// at a reset starts over once the controller is back up.
+const PIN_LOCKOUT_TIME: Duration = Duration::from_secs(300);
+
+/// Stored PIN state: `[ PIN LSB ] .. [ PIN MSB ] [ failures ] [ locked out ] [ 0 ] [ 0 ]`, so a
+/// reset does not give an attacker a fresh set of tries.
+const PIN_BYTES: usize = 8;
+/// The PIN uses storage pages 4 and 5.
+static PIN_JOURNAL: Journal<{ PIN_BYTES + RECORD_OVERHEAD }> = Journal::new(4);
+
+struct PinState {
+    /// `0` disables PIN protection.
+    pin: u32,
+    unlocked: bool,
+    failures: u8,
+    locked_out_until: Option<Instant>,
+}
+
+static PIN_STATE: Mutex<ThreadModeRawMutex, Option<PinState>> = Mutex::new(None);
+
+async fn load() -> PinState {
+    let mut payload = [0; PIN_BYTES];
+    match PIN_JOURNAL.load(&mut payload).await {
+        Ok(true) => {}
+        Ok(false) => payload = [0; PIN_BYTES],
+        Err(error) => {
+            // Failing closed would lock the host out of a device it cannot repair remotely.
+            error!("failed to load the PIN, protection disabled: {}", error);
+            payload = [0; PIN_BYTES];
+        }
+    }
+    let locked_out = payload[5] != 0;
+    if locked_out {
+        warn!("PIN entry locked out before the reset, the lockout starts over");
+    }
+    PinState {
+        pin: u32::from_le_bytes([payload[0], payload[1], payload[2], payload[3]]),
+        unlocked: false,
+        failures: payload[4],
+        locked_out_until: locked_out.then(|| Instant::now() + PIN_LOCKOUT_TIME),
+    }
+}
+
+impl PinState {
+    fn is_locked_out(&self) -> bool {
+        self.locked_out_until
+            .is_some_and(|until| Instant::now() < until)
+    }
+
+    /// Saves the PIN with the failure count and the lockout.
+    async fn save(&self) -> Result<(), StorageError> {
+        let mut payload = [0; PIN_BYTES];
+        payload[..4].copy_from_slice(&self.pin.to_le_bytes());
+        payload[4] = self.failures;
+        payload[5] = u8::from(self.is_locked_out());
+        PIN_JOURNAL.save(&payload).await
+    }
+}
+
+/// The PIN state, loaded from flash on first use.
+async fn loaded(state: &mut Option<PinState>) -> &mut PinState {
+    let loaded = match state.take() {
+        Some(loaded) => loaded,
+        None => load().await,
+    };
+    state.insert(loaded)
+}
+
+/// Whether PIN protected commands are allowed, either because the PIN was entered since the last
+/// reset or because no PIN is set.
+pub async fn is_unlocked() -> bool {
+    let mut guard = PIN_STATE.lock().await;
+    let state = loaded(&mut guard).await;
+    let unlocked = state.pin == 0 || state.unlocked;
+    drop(guard);
+    unlocked
+}
+
+/// Handles ccTalk "enter PIN number" (header 218). The host always gets an ACK, wrong PINs only
+/// after [`PIN_FAILURE_DELAY`].
+pub async fn enter_pin(pin: u32) {
+    let mut guard = PIN_STATE.lock().await;
+    let state = loaded(&mut guard).await;
+
+    if state.is_locked_out() {
+        warn!("PIN entry ignored, locked out");
+        drop(guard);
+        Timer::after(PIN_FAILURE_DELAY).await;
+        return;
+    }
+
+    if pin == state.pin {
+        info!("PIN accepted");
+        state.unlocked = true;
+        let had_failures = state.failures > 0 || state.locked_out_until.is_some();
+        state.failures = 0;
+        state.locked_out_until = None;
+        if had_failures {
+            save_failures(state).await;
+        }
+        return;
+    }
+
+    state.failures = state.failures.saturating_add(1);
+    warn!("wrong PIN entered, {} failures", state.failures);
+    if state.failures >= PIN_MAX_FAILURES {
+        state.failures = 0;
+        state.locked_out_until = Some(Instant::now() + PIN_LOCKOUT_TIME);
+        log_event(Event::PinLockout);
+    }
+    save_failures(state).await;
+    drop(guard);
+    Timer::after(PIN_FAILURE_DELAY).await;
+}
+
+/// Saves the failure count and the lockout, they are then only kept until the next reset if the
+/// flash cannot be written.
+async fn save_failures(state: &PinState) {
+    if let Err(error) = state.save().await {
+        error!("failed to save the PIN failures: {}", error);
+    }
+}
+
+/// Handles ccTalk "enter new PIN number" (header 219), a new PIN of `0` disables the protection.
+/// The caller checks that the device is unlocked.
+///
+/// # Errors
+///
+/// If the new PIN cannot be saved, the old one then stays in use.
+pub async fn change_pin(pin: u32) -> Result<(), StorageError> {
+    let mut guard = PIN_STATE.lock().await;
+    let state = loaded(&mut guard).await;
+    let changed = PinState {
+        pin,
+        unlocked: true,
+        failures: 0,
+        locked_out_until: None,
+    };
+    changed.save().await?;
+    *state = changed;
+    drop(guard);
+
+    info!("PIN changed");
+    log_event(Event::PinChanged);
+    Ok(())
+}
@@ -0,0 +1,43 @@
+use embassy_time::{Duration, Timer};
+
+use crate::{
+    board::CcTalkBus,
+    payout::{get_payout_status, is_motor_running},
+};
+
+/// Bus silence after which the ccTalk UART is released, the chip can then idle in STOP mode
+/// until the next frame starts. That frame is lost, the host gets its reply on the retry.
+const STOP_AFTER_BUS_IDLE: Duration = Duration::from_secs(2);
+
+/// Whether the chip can idle in STOP mode, which needs a `low-power` build on a board whose
+/// ccTalk bus can wake the chip.
+pub const STOP_SUPPORTED: bool = cfg!(feature = "low-power") && CcTalkBus::CAN_WAKE;
+
+/// Resolves once the ccTalk bus was quiet for [`STOP_AFTER_BUS_IDLE`], never without
+/// [`STOP_SUPPORTED`] so frames are not cut short for nothing.
+pub async fn bus_idle_timeout() {
+    if STOP_SUPPORTED {
+        Timer::after(STOP_AFTER_BUS_IDLE).await;
+    } else {
+        core::future::pending::<()>().await;
+    }
+}
+
+/// Whether the ccTalk UART may be released: the motor is off and no payout is pending.
+pub async fn stop_allowed() -> bool {
+    !is_motor_running().await && get_payout_status().await.coins_remaining == 0
+}
+
+/// Hands the RTC to the low-power executor, which keeps time with it while in STOP mode.
+///
+/// # Panics
+///
+/// If called more than once.
+#[cfg(feature = "low-power")]
+pub fn init_low_power(rtc: embassy_stm32::Peri<'static, embassy_stm32::peripherals::RTC>) {
+    use embassy_stm32::rtc::{Rtc, RtcConfig};
+
+    let rtc = cortex_m::singleton!(: Rtc = Rtc::new(rtc, RtcConfig::default()))
+        .expect("low power should be initialized once");
+    embassy_stm32::low_power::stop_with_rtc(rtc);
+}
@@ -0,0 +1,191 @@
+use embassy_stm32::gpio::Level;
+use embassy_time::Duration;
+
+use crate::{config::PayoutConfig, level::LevelSensorConfig};
+
+/// How the hopper is reset through its IN1 and IN2 inputs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum ResetSequence {
+    /// IN1 driven low and IN2 high at the same time, as on the MK2.
+    In1LowIn2High,
+    /// IN1 pulsed low, IN2 left alone.
+    In1Low,
+    /// The hopper has no reset input, a hopper reset only clears the adapter state.
+    NotSupported,
+}
+
+/// Wiring and timing of a hopper model driven through the parallel interface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct HopperProfile {
+    /// Name given at build time through `HOPPER_PROFILE`.
+    pub name: &'static str,
+    /// Level of IN3 that runs the motor.
+    pub motor_on_level: Level,
+    /// Level of the exit opto output while a coin blocks it.
+    pub opto_blocked_level: Level,
+    /// Level of the security output once it tripped.
+    pub security_tripped_level: Level,
+    pub low_level_sensor: LevelSensorConfig,
+    pub high_level_sensor: LevelSensorConfig,
+    pub reset_sequence: ResetSequence,
+    /// Time the reset levels are held.
+    pub reset_pulse: Duration,
+    /// Nominal payout rate, in coins per second.
+    pub coin_rate: u8,
+    /// Time a level plate has to stay covered before it is reported as covered. Coins bouncing
+    /// past the plates during a payout cover them for a short time only.
+    pub level_debounce_covered: Duration,
+    /// Time a level plate has to stay uncovered before it is reported as uncovered. Kept short
+    /// so the host learns quickly that the hopper ran low.
+    pub level_debounce_uncovered: Duration,
+    /// Payout configuration used until the host sets one through the variable set.
+    pub payout_config: PayoutConfig,
+}
+
+/// Motor run time allowed per coin for a nominal `coin_rate`, three coin periods.
+const fn max_time_per_coin(coin_rate: u8) -> Duration {
+    Duration::from_millis(3_000 / coin_rate as u64)
+}
+
+impl HopperProfile {
+    /// Universal Hopper MK2 in its standard version, the wiring the adapter was designed for.
+    pub const MK2_STANDARD: Self = Self {
+        name: "mk2-standard",
+        motor_on_level: Level::High,
+        opto_blocked_level: Level::Low,
+        security_tripped_level: Level::Low,
+        low_level_sensor: LevelSensorConfig {
+            fitted: true,
+            active_level: Level::Low,
+        },
+        high_level_sensor: LevelSensorConfig {
+            fitted: true,
+            active_level: Level::Low,
+        },
+        reset_sequence: ResetSequence::In1LowIn2High,
+        reset_pulse: Duration::from_millis(50),
+        coin_rate: 6,
+        level_debounce_covered: Duration::from_millis(1_000),
+        level_debounce_uncovered: Duration::from_millis(250),
+        payout_config: PayoutConfig {
+            min_brake_time: Duration::from_millis(50),
+            min_detection_time: Duration::from_millis(30),
+            max_detection_time: Duration::from_millis(300),
+            motor_max_time_per_coin: max_time_per_coin(6),
+            empty_no_coin_timeout: Duration::from_millis(1_500),
+            book_keeper_poll_interval: Duration::from_secs(5),
+            book_keeper_max_tries: 2,
+            overpay_window: Duration::from_millis(1_000),
+            overpay_compensation: false,
+            end_payout_when_empty: true,
+        },
+    };
+
+    /// MK2 with the high-speed motor, coins pass the exit opto faster and the motor needs longer
+    /// to brake.
+    pub const MK2_HIGH_SPEED: Self = Self {
+        name: "mk2-high-speed",
+        coin_rate: 10,
+        payout_config: PayoutConfig {
+            min_brake_time: Duration::from_millis(80),
+            min_detection_time: Duration::from_millis(15),
+            max_detection_time: Duration::from_millis(200),
+            motor_max_time_per_coin: max_time_per_coin(10),
+            empty_no_coin_timeout: Duration::from_millis(1_000),
+            ..Self::MK2_STANDARD.payout_config
+        },
+        ..Self::MK2_STANDARD
+    };
+
+    /// Compact Hopper, reset through IN1 only and a slower coin rate.
+    pub const COMPACT: Self = Self {
+        name: "compact",
+        reset_sequence: ResetSequence::In1Low,
+        reset_pulse: Duration::from_millis(100),
+        coin_rate: 4,
+        payout_config: PayoutConfig {
+            min_detection_time: Duration::from_millis(40),
+            max_detection_time: Duration::from_millis(400),
+            motor_max_time_per_coin: max_time_per_coin(4),
+            empty_no_coin_timeout: Duration::from_millis(2_000),
+            ..Self::MK2_STANDARD.payout_config
+        },
+        ..Self::MK2_STANDARD
+    };
+
+    /// Any other hopper with a motor input and an exit opto, without a reset input or level
+    /// plates. Timings are kept loose so they suit most coins.
+    pub const GENERIC_PARALLEL: Self = Self {
+        name: "generic-parallel",
+        low_level_sensor: LevelSensorConfig {
+            fitted: false,
+            active_level: Level::Low,
+        },
+        high_level_sensor: LevelSensorConfig {
+            fitted: false,
+            active_level: Level::Low,
+        },
+        reset_sequence: ResetSequence::NotSupported,
+        coin_rate: 3,
+        payout_config: PayoutConfig {
+            min_brake_time: Duration::from_millis(100),
+            min_detection_time: Duration::from_millis(20),
+            max_detection_time: Duration::from_millis(500),
+            motor_max_time_per_coin: max_time_per_coin(3),
+            empty_no_coin_timeout: Duration::from_millis(3_000),
+            ..Self::MK2_STANDARD.payout_config
+        },
+        ..Self::MK2_STANDARD
+    };
+
+    pub const ALL: [Self; 4] = [
+        Self::MK2_STANDARD,
+        Self::MK2_HIGH_SPEED,
+        Self::COMPACT,
+        Self::GENERIC_PARALLEL,
+    ];
+
+    /// Picks the profile named at build time, the MK2 standard one by default.
+    const fn parse(value: Option<&str>) -> Self {
+        let Some(value) = value else {
+            return Self::MK2_STANDARD;
+        };
+
+        let mut i = 0;
+        while i < Self::ALL.len() {
+            if const_str_eq(value, Self::ALL[i].name) {
+                return Self::ALL[i];
+            }
+            i += 1;
+        }
+        panic!(
+            "hopper profile must be `mk2-standard`, `mk2-high-speed`, `compact` or \
+             `generic-parallel`"
+        );
+    }
+
+    /// Level of IN3 that keeps the motor off.
+    #[must_use]
+    pub fn motor_off_level(&self) -> Level {
+        Level::from(self.motor_on_level == Level::Low)
+    }
+}
+
+const fn const_str_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+
+    let mut i = 0;
+    while i < a.len() {
+        if a[i] != b[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Hopper model the firmware drives, set at build time through `HOPPER_PROFILE`.
+pub const HOPPER_PROFILE: HopperProfile = HopperProfile::parse(option_env!("HOPPER_PROFILE"));
@@ -0,0 +1,75 @@
+use core::cell::Cell;
+
+use defmt::{info, warn};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+
+use crate::{
+    audit::{increment, request_audit_save, AuditCounter},
+    events::{log_event, Event},
+    fill::{add_coins, fill_estimate, set_fill_estimate, HIGH_LEVEL_COINS},
+    level::{get_level_sensors, HIGH_LEVEL_SENSOR_CONFIG},
+};
+
+/// Fill estimate when the running refill was started, `None` outside of a refill.
+static REFILL_START: Mutex<CriticalSectionRawMutex, Cell<Option<u16>>> =
+    Mutex::new(Cell::new(None));
+
+/// Whether a refill is in progress, payouts are held back until it ends.
+pub fn refill_in_progress() -> bool {
+    REFILL_START.lock(Cell::get).is_some()
+}
+
+/// Starts a refill, the attendant can then pour coins into the hopper without the motor
+/// starting.
+///
+/// Refills are started and ended through handheld functions 2 and 3, the adapter has no service
+/// console to trigger them from.
+pub fn begin_refill() {
+    let coins = fill_estimate().coins;
+    REFILL_START.lock(|start| start.set(Some(coins)));
+    info!("refill started at {} coins", coins);
+}
+
+/// Ends the running refill.
+///
+/// With a `counted` number of coins the fill estimate is increased by that count. Without it, the
+/// added coins are only known when the high level plate is covered, the hopper is then taken as
+/// filled to the high level.
+pub async fn end_refill(counted: Option<u16>) {
+    let Some(start) = REFILL_START.lock(Cell::take) else {
+        warn!("no refill in progress");
+        return;
+    };
+
+    let (_, high) = get_level_sensors().await;
+    match counted {
+        Some(coins) => {
+            // The plates may have recalibrated the estimate while coins were poured in, the count
+            // is relative to the estimate at the start.
+            set_fill_estimate(start.saturating_add(coins));
+            log_refill(coins, true);
+        }
+        None if HIGH_LEVEL_SENSOR_CONFIG.fitted && high.covered => {
+            let coins = HIGH_LEVEL_COINS.saturating_sub(start);
+            set_fill_estimate(start.max(HIGH_LEVEL_COINS));
+            log_refill(coins, false);
+        }
+        None => {
+            warn!("refill ended without a count and the high level plate uncovered");
+            log_refill(0, false);
+        }
+    }
+}
+
+/// Records a refill of `coins` counted by the attendant.
+pub fn record_refill(coins: u16) {
+    add_coins(coins);
+    log_refill(coins, true);
+}
+
+fn log_refill(coins: u16, counted: bool) {
+    log_event(Event::Refilled { coins, counted });
+    increment(AuditCounter::Refills, 1);
+    increment(AuditCounter::CoinsRefilled, u32::from(coins));
+    request_audit_save();
+}
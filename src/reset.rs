@@ -1,7 +1,12 @@
 use defmt::info;
 use embassy_stm32::gpio::Output;
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
-use embassy_time::{Duration, Timer};
+use embassy_time::Timer;
+
+use crate::{
+    audit::{flush_audit_counters, increment, AuditCounter},
+    profile::{ResetSequence, HOPPER_PROFILE},
+};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
 pub enum ResetType {
@@ -20,7 +25,8 @@ pub fn send_reset_signal(reset_type: ResetType) {
 /// Background task that listens for reset signals and performs the appropriate reset action.
 /// This task will reset the hopper, controller, or both based on the received signal.
 ///
-/// The hopper reset is done by driving the `in_1` and `in_2` outputs to low and high respectively.
+/// The hopper reset drives the `in_1` and `in_2` outputs as the reset sequence of the hopper
+/// profile asks.
 ///
 /// The system reset is performed by calling the system control block's reset function.
 #[embassy_executor::task]
@@ -38,11 +44,13 @@ pub async fn reset_task(mut in_1: Output<'static>, mut in_2: Output<'static>) {
             }
             ResetType::Controller => {
                 info!("Resetting controller");
+                flush_audit_counters().await;
                 cortex_m::peripheral::SCB::sys_reset();
             }
             ResetType::All => {
                 info!("Resetting all");
                 reset_hopper(&mut in_1, &mut in_2).await;
+                flush_audit_counters().await;
                 cortex_m::peripheral::SCB::sys_reset();
             }
         }
@@ -51,13 +59,23 @@ pub async fn reset_task(mut in_1: Output<'static>, mut in_2: Output<'static>) {
 
 async fn reset_hopper(in_1: &mut Output<'static>, in_2: &mut Output<'static>) {
     info!("Resetting hopper");
+    increment(AuditCounter::HopperResets, 1);
 
     let in_1_initial_state = in_1.get_output_level();
     let in_2_initial_state = in_2.get_output_level();
 
-    in_1.set_low();
-    in_2.set_high();
-    Timer::after(Duration::from_millis(50)).await;
+    match HOPPER_PROFILE.reset_sequence {
+        ResetSequence::In1LowIn2High => {
+            in_1.set_low();
+            in_2.set_high();
+        }
+        ResetSequence::In1Low => in_1.set_low(),
+        ResetSequence::NotSupported => {
+            info!("hopper has no reset input");
+            return;
+        }
+    }
+    Timer::after(HOPPER_PROFILE.reset_pulse).await;
     in_1.set_level(in_1_initial_state);
     in_2.set_level(in_2_initial_state);
 }
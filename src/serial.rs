@@ -0,0 +1,186 @@
+use core::{
+    ptr::{read_volatile, write_volatile},
+    sync::atomic::{fence, Ordering},
+};
+
+use cc_talk_core::cc_talk::SerialCode;
+use defmt::{info, warn};
+use embassy_stm32::{flash, pac::FLASH};
+
+use crate::{
+    events::{log_event, Event},
+    payout::is_motor_running,
+    storage::{with_flash, StorageError},
+};
+
+/// Double word of the OTP area holding the serial number, programmed once at manufacturing:
+/// `[ serial 1 ] .. [ serial 3 ] [ !serial 1 ] .. [ !serial 3 ] [ 'S' ] [ 'N' ]`
+///
+/// The serial is LSB first, as in the reply to header 242, the complement tells it from a double
+/// word left blank or garbled. OTP cannot be erased, so a serial stays with the controller
+/// through firmware upgrades and storage resets.
+const SERIAL_OTP_ADDRESS: usize = 0x1FFF_7000;
+const SERIAL_MARKER: [u8; 2] = *b"SN";
+
+/// Serial number used until one is provisioned, set at build time through `HOPPER_SERIAL_CODE`
+/// as `major,minor,fix` (`0,215,0` by default) and kept in that order. A malformed value fails
+/// the build.
+pub const BUILD_SERIAL_CODE: [u8; 3] = match parse_serial_code(option_env!("HOPPER_SERIAL_CODE")) {
+    Ok(code) => code,
+    Err(SerialCodeError::Malformed) => {
+        panic!("serial code must be three numbers, as `major,minor,fix`")
+    }
+    Err(SerialCodeError::OutOfRange) => panic!("serial code numbers must be between 0 and 255"),
+};
+
+/// Reason a build time serial code is rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum SerialCodeError {
+    /// Not three comma separated numbers.
+    Malformed,
+    /// A number is above 255.
+    OutOfRange,
+}
+
+pub(crate) const fn parse_serial_code(value: Option<&str>) -> Result<[u8; 3], SerialCodeError> {
+    let Some(value) = value else {
+        return Ok([0, 215, 0]);
+    };
+
+    let bytes = value.as_bytes();
+    let mut fields = [0u8; 3];
+    let mut field = 0;
+    let mut digits = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b',' {
+            if digits == 0 || field == 2 {
+                return Err(SerialCodeError::Malformed);
+            }
+            field += 1;
+            digits = 0;
+        } else {
+            if !bytes[i].is_ascii_digit() {
+                return Err(SerialCodeError::Malformed);
+            }
+            let Some(shifted) = fields[field].checked_mul(10) else {
+                return Err(SerialCodeError::OutOfRange);
+            };
+            let Some(next) = shifted.checked_add(bytes[i] - b'0') else {
+                return Err(SerialCodeError::OutOfRange);
+            };
+            fields[field] = next;
+            digits += 1;
+        }
+        i += 1;
+    }
+    if digits == 0 || field != 2 {
+        return Err(SerialCodeError::Malformed);
+    }
+    Ok(fields)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum SerialError {
+    /// The OTP double word was already programmed, a serial can only be written once.
+    AlreadyProvisioned,
+    /// Writing flash stalls the CPU, it is not done while the motor runs.
+    Busy,
+    Storage(StorageError),
+}
+
+impl From<StorageError> for SerialError {
+    fn from(error: StorageError) -> Self {
+        Self::Storage(error)
+    }
+}
+
+fn read_otp() -> [u8; 8] {
+    let mut bytes = [0; 8];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        // SAFETY: the OTP area is always mapped and readable.
+        *byte = unsafe { read_volatile((SERIAL_OTP_ADDRESS + i) as *const u8) };
+    }
+    bytes
+}
+
+/// Serial number provisioned in OTP, `None` if there is none.
+fn provisioned_serial() -> Option<SerialCode> {
+    let bytes = read_otp();
+    let [fix, minor, major, ..] = bytes;
+    let is_valid = bytes[3..6] == [!fix, !minor, !major] && bytes[6..] == SERIAL_MARKER;
+    is_valid.then(|| SerialCode::new(major, minor, fix))
+}
+
+/// Serial number of the adapter, the one provisioned in OTP or else [`BUILD_SERIAL_CODE`].
+#[must_use]
+pub fn serial_number() -> SerialCode {
+    provisioned_serial().unwrap_or_else(|| {
+        let [major, minor, fix] = BUILD_SERIAL_CODE;
+        SerialCode::new(major, minor, fix)
+    })
+}
+
+/// Programs `serial` in OTP (handheld function 7), once at manufacturing.
+///
+/// # Errors
+///
+/// If a serial was already programmed, the motor is running or the flash cannot be written.
+pub async fn provision_serial(serial: SerialCode) -> Result<(), SerialError> {
+    if read_otp() != [0xFF; 8] {
+        return Err(SerialError::AlreadyProvisioned);
+    }
+    if is_motor_running().await {
+        return Err(SerialError::Busy);
+    }
+
+    let (fix, minor, major) = (serial.fix(), serial.minor(), serial.major());
+    let [marker_1, marker_2] = SERIAL_MARKER;
+    let low = u32::from_le_bytes([fix, minor, major, !fix]);
+    let high = u32::from_le_bytes([!minor, !major, marker_1, marker_2]);
+    // The flash driver cannot reach the OTP area, it is programmed through the registers while
+    // the driver is held so nothing else uses the flash meanwhile.
+    with_flash(|_| program_otp(low, high).map_err(StorageError::from)).await?;
+
+    info!("serial number provisioned: {}", serial);
+    log_event(Event::SerialProvisioned);
+    Ok(())
+}
+
+fn program_otp(low: u32, high: u32) -> Result<(), flash::Error> {
+    let wait_ready = || while FLASH.sr().read().bsy() || FLASH.sr().read().bsy2() {};
+
+    wait_ready();
+    // Clears the error flags, they are written back as 1.
+    FLASH.sr().modify(|_| {});
+    if FLASH.cr().read().lock() {
+        FLASH.keyr().write_value(0x4567_0123);
+        FLASH.keyr().write_value(0xCDEF_89AB);
+    }
+    FLASH.cr().write(|w| w.set_pg(true));
+    // SAFETY: programming is enabled and the double word is erased, both halves are written in
+    // order as the flash interface requires.
+    unsafe {
+        write_volatile(SERIAL_OTP_ADDRESS as *mut u32, low);
+        fence(Ordering::SeqCst);
+        write_volatile((SERIAL_OTP_ADDRESS + 4) as *mut u32, high);
+        fence(Ordering::SeqCst);
+    }
+    wait_ready();
+    FLASH.cr().write(|w| w.set_pg(false));
+    FLASH.cr().modify(|w| w.set_lock(true));
+
+    let status = FLASH.sr().read();
+    if status.progerr() {
+        warn!("OTP double word was not erased");
+        Err(flash::Error::Prog)
+    } else if status.wrperr() {
+        Err(flash::Error::Protected)
+    } else if status.pgaerr() {
+        Err(flash::Error::Unaligned)
+    } else if status.pgserr() {
+        Err(flash::Error::Seq)
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,243 @@
+use defmt::{debug, warn};
+use embassy_stm32::{
+    flash::{self, Blocking, Flash, FLASH_SIZE, MAX_ERASE_SIZE, WRITE_SIZE},
+    peripherals::FLASH,
+    Peri,
+};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, mutex::Mutex};
+
+/// Erase unit of the flash.
+pub const PAGE_SIZE: u32 = MAX_ERASE_SIZE as u32;
+/// Size of the storage area at the end of flash, kept out of the application by `memory.x`.
+const STORAGE_SIZE: u32 = 16 * 1024;
+const STORAGE_OFFSET: u32 = FLASH_SIZE as u32 - STORAGE_SIZE;
+/// Number of pages in the storage area.
+pub const STORAGE_PAGES: u32 = STORAGE_SIZE / PAGE_SIZE;
+/// Bytes added to each journal record: a sequence number and a CRC-32.
+pub const RECORD_OVERHEAD: usize = 8;
+
+static FLASH_DRIVER: Mutex<ThreadModeRawMutex, Option<Flash<'static, Blocking>>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum StorageError {
+    /// [`init_storage`] was not called yet.
+    NotInitialized,
+    Flash(flash::Error),
+}
+
+impl From<flash::Error> for StorageError {
+    fn from(error: flash::Error) -> Self {
+        Self::Flash(error)
+    }
+}
+
+/// Hands the flash over to the storage, journals cannot be used before.
+pub async fn init_storage(flash: Peri<'static, FLASH>) {
+    *FLASH_DRIVER.lock().await = Some(Flash::new_blocking(flash));
+}
+
+/// Runs `f` with the flash driver, for the users of flash outside of the journals.
+///
+/// # Errors
+///
+/// If the storage is not initialized, or as returned by `f`.
+pub(crate) async fn with_flash<R, E: From<StorageError>>(
+    f: impl FnOnce(&mut Flash<'static, Blocking>) -> Result<R, E>,
+) -> Result<R, E> {
+    let mut flash = FLASH_DRIVER.lock().await;
+    f(flash.as_mut().ok_or(StorageError::NotInitialized)?)
+}
+
+/// Bitwise CRC-32 (IEEE), records are small enough to do without a table.
+fn crc32(bytes: &[u8]) -> u32 {
+    !crc32_update(!0, bytes)
+}
+
+/// Feeds `bytes` to a running CRC-32, which starts at `!0` and is inverted once complete.
+pub(crate) fn crc32_update(mut crc: u32, bytes: &[u8]) -> u32 {
+    for byte in bytes {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+/// Position of the latest record of a journal.
+#[derive(Clone, Copy)]
+struct Latest {
+    page: u32,
+    slot: u32,
+    sequence: u32,
+}
+
+/// Fixed size records kept in two alternating flash pages of the storage area.
+///
+/// Records are appended until a page is full, the other page is then erased and written. The
+/// latest record is always left intact, so power loss during a save only loses the record being
+/// written. `SIZE` is the record size including [`RECORD_OVERHEAD`].
+pub struct Journal<const SIZE: usize> {
+    first_page: u32,
+}
+
+impl<const SIZE: usize> Journal<SIZE> {
+    const SLOTS_PER_PAGE: u32 = PAGE_SIZE / SIZE as u32;
+
+    /// Creates a journal using storage pages `first_page` and `first_page + 1`.
+    ///
+    /// # Panics
+    ///
+    /// At compile time if the record size is not a multiple of the flash write size, or if the
+    /// pages are outside of the storage area.
+    #[must_use]
+    pub const fn new(first_page: u32) -> Self {
+        assert!(SIZE > RECORD_OVERHEAD && SIZE.is_multiple_of(WRITE_SIZE));
+        assert!(first_page + 1 < STORAGE_PAGES);
+        Self { first_page }
+    }
+
+    pub(crate) const fn page_offset(&self, page: u32) -> u32 {
+        STORAGE_OFFSET + (self.first_page + page) * PAGE_SIZE
+    }
+
+    pub(crate) const fn slot_offset(&self, page: u32, slot: u32) -> u32 {
+        self.page_offset(page) + slot * SIZE as u32
+    }
+
+    fn scan(
+        flash: &mut Flash<'static, Blocking>,
+        offset: u32,
+    ) -> Result<Option<u32>, StorageError> {
+        let mut record = [0u8; SIZE];
+        flash.blocking_read(offset, &mut record)?;
+        let (body, crc) = record.split_at(SIZE - 4);
+        let crc = u32::from_le_bytes([crc[0], crc[1], crc[2], crc[3]]);
+        if crc != crc32(body) {
+            return Ok(None);
+        }
+        let sequence = &body[SIZE - RECORD_OVERHEAD..];
+        Ok(Some(u32::from_le_bytes([
+            sequence[0],
+            sequence[1],
+            sequence[2],
+            sequence[3],
+        ])))
+    }
+
+    fn latest(&self, flash: &mut Flash<'static, Blocking>) -> Result<Option<Latest>, StorageError> {
+        let mut latest: Option<Latest> = None;
+        for page in 0..2 {
+            for slot in 0..Self::SLOTS_PER_PAGE {
+                let Some(sequence) = Self::scan(flash, self.slot_offset(page, slot))? else {
+                    continue;
+                };
+                if latest.is_none_or(|latest| sequence > latest.sequence) {
+                    latest = Some(Latest {
+                        page,
+                        slot,
+                        sequence,
+                    });
+                }
+            }
+        }
+        Ok(latest)
+    }
+
+    fn is_blank(flash: &mut Flash<'static, Blocking>, offset: u32) -> Result<bool, StorageError> {
+        let mut record = [0u8; SIZE];
+        flash.blocking_read(offset, &mut record)?;
+        Ok(record.iter().all(|byte| *byte == 0xFF))
+    }
+
+    /// Loads the latest record into `payload`, returns `false` if the journal is empty.
+    ///
+    /// # Errors
+    ///
+    /// If the storage is not initialized or the flash cannot be read.
+    ///
+    /// # Panics
+    ///
+    /// If `payload` is not `SIZE - RECORD_OVERHEAD` bytes long.
+    pub async fn load(&self, payload: &mut [u8]) -> Result<bool, StorageError> {
+        assert_eq!(payload.len(), SIZE - RECORD_OVERHEAD);
+        let mut flash = FLASH_DRIVER.lock().await;
+        self.load_from(flash.as_mut().ok_or(StorageError::NotInitialized)?, payload)
+    }
+
+    fn load_from(
+        &self,
+        flash: &mut Flash<'static, Blocking>,
+        payload: &mut [u8],
+    ) -> Result<bool, StorageError> {
+        let Some(latest) = self.latest(flash)? else {
+            return Ok(false);
+        };
+        flash.blocking_read(self.slot_offset(latest.page, latest.slot), payload)?;
+        Ok(true)
+    }
+
+    /// Appends a record holding `payload`.
+    ///
+    /// # Errors
+    ///
+    /// If the storage is not initialized or the flash cannot be erased or written.
+    ///
+    /// # Panics
+    ///
+    /// If `payload` is not `SIZE - RECORD_OVERHEAD` bytes long.
+    pub async fn save(&self, payload: &[u8]) -> Result<(), StorageError> {
+        assert_eq!(payload.len(), SIZE - RECORD_OVERHEAD);
+        let mut flash = FLASH_DRIVER.lock().await;
+        self.save_to(flash.as_mut().ok_or(StorageError::NotInitialized)?, payload)
+    }
+
+    fn save_to(
+        &self,
+        flash: &mut Flash<'static, Blocking>,
+        payload: &[u8],
+    ) -> Result<(), StorageError> {
+        let latest = self.latest(flash)?;
+        let sequence = latest.map_or(0, |latest| latest.sequence.wrapping_add(1));
+
+        // Next blank slot after the latest record, erasing the other page once this one is full.
+        let mut target = None;
+        if let Some(latest) = latest {
+            for slot in latest.slot + 1..Self::SLOTS_PER_PAGE {
+                if Self::is_blank(flash, self.slot_offset(latest.page, slot))? {
+                    target = Some((latest.page, slot));
+                    break;
+                }
+            }
+        }
+        let (page, slot) = if let Some(target) = target {
+            target
+        } else {
+            let page = latest.map_or(0, |latest| 1 - latest.page);
+            debug!("erasing storage page {}", self.first_page + page);
+            let offset = self.page_offset(page);
+            flash.blocking_erase(offset, offset + PAGE_SIZE)?;
+            (page, 0)
+        };
+
+        let mut record = [0xFF; SIZE];
+        record[..payload.len()].copy_from_slice(payload);
+        record[SIZE - RECORD_OVERHEAD..SIZE - 4].copy_from_slice(&sequence.to_le_bytes());
+        let crc = crc32(&record[..SIZE - 4]);
+        record[SIZE - 4..].copy_from_slice(&crc.to_le_bytes());
+
+        if let Err(error) = flash.blocking_write(self.slot_offset(page, slot), &record) {
+            warn!(
+                "failed to write storage page {}: {}",
+                self.first_page + page,
+                error
+            );
+            return Err(error.into());
+        }
+        Ok(())
+    }
+}
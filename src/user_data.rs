@@ -0,0 +1,106 @@
+use defmt::{error, info, warn};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, mutex::Mutex};
+
+use crate::{
+    payout::is_motor_running,
+    storage::{Journal, StorageError, RECORD_OVERHEAD},
+};
+
+/// Number of ccTalk data blocks (headers 214/215) in the user data area.
+pub const USER_DATA_BLOCKS: u8 = 8;
+/// Size of a ccTalk data block, reads and writes always cover a whole block.
+pub const USER_DATA_BLOCK_SIZE: u8 = 32;
+const USER_DATA_SIZE: usize = USER_DATA_BLOCKS as usize * USER_DATA_BLOCK_SIZE as usize;
+
+/// The user data area uses storage pages 2 and 3, every block write saves the whole area.
+static USER_DATA_JOURNAL: Journal<{ USER_DATA_SIZE + RECORD_OVERHEAD }> = Journal::new(2);
+/// Copy of the user data area, loaded from flash on first use.
+static USER_DATA: Mutex<ThreadModeRawMutex, Option<[u8; USER_DATA_SIZE]>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum UserDataError {
+    /// The block number is past the user data area.
+    InvalidBlock,
+    /// The data does not fill exactly one block.
+    InvalidLength,
+    /// Writing flash stalls the CPU, it is not done while the motor runs.
+    Busy,
+    Storage(StorageError),
+}
+
+impl From<StorageError> for UserDataError {
+    fn from(error: StorageError) -> Self {
+        Self::Storage(error)
+    }
+}
+
+fn block_range(block: u8) -> Result<core::ops::Range<usize>, UserDataError> {
+    if block >= USER_DATA_BLOCKS {
+        return Err(UserDataError::InvalidBlock);
+    }
+    let start = usize::from(block) * usize::from(USER_DATA_BLOCK_SIZE);
+    Ok(start..start + usize::from(USER_DATA_BLOCK_SIZE))
+}
+
+/// Loads the user data area, it reads as erased flash until written.
+async fn load() -> [u8; USER_DATA_SIZE] {
+    let mut data = [0xFF; USER_DATA_SIZE];
+    match USER_DATA_JOURNAL.load(&mut data).await {
+        Ok(true) => {}
+        Ok(false) => info!("no user data stored"),
+        Err(error) => error!("failed to load user data: {}", error),
+    }
+    data
+}
+
+/// Reads a user data block.
+///
+/// # Errors
+///
+/// If the block number is past the user data area.
+pub async fn read_block(block: u8) -> Result<[u8; USER_DATA_BLOCK_SIZE as usize], UserDataError> {
+    let range = block_range(block)?;
+    let mut user_data = USER_DATA.lock().await;
+    let data = match *user_data {
+        Some(data) => data,
+        None => *user_data.insert(load().await),
+    };
+    drop(user_data);
+
+    let mut block = [0; USER_DATA_BLOCK_SIZE as usize];
+    block.copy_from_slice(&data[range]);
+    Ok(block)
+}
+
+/// Writes a user data block, the write is saved to flash before returning.
+///
+/// # Errors
+///
+/// If the block number is past the user data area, the data does not fill the block, the motor
+/// is running or the flash cannot be written.
+pub async fn write_block(block: u8, bytes: &[u8]) -> Result<(), UserDataError> {
+    let range = block_range(block)?;
+    if bytes.len() != usize::from(USER_DATA_BLOCK_SIZE) {
+        return Err(UserDataError::InvalidLength);
+    }
+    if is_motor_running().await {
+        warn!(
+            "user data block {} not written, the motor is running",
+            block
+        );
+        return Err(UserDataError::Busy);
+    }
+
+    let mut user_data = USER_DATA.lock().await;
+    let mut data = match *user_data {
+        Some(data) => data,
+        None => load().await,
+    };
+    data[range].copy_from_slice(bytes);
+    USER_DATA_JOURNAL.save(&data).await?;
+    *user_data = Some(data);
+    drop(user_data);
+
+    info!("user data block {} written", block);
+    Ok(())
+}
@@ -0,0 +1,213 @@
+use core::{cell::Cell, future::Future};
+
+use defmt::{error, info, warn};
+use embassy_executor::Spawner;
+use embassy_futures::select::select;
+use embassy_stm32::{pac, peripherals::IWDG, wdg::IndependentWatchdog, Peri};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use embassy_time::{Duration, Instant, Timer};
+use portable_atomic::{AtomicU64, Ordering};
+
+use crate::events::{log_event, Event};
+
+/// Tasks that have to make progress for the watchdog to be fed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum SupervisedTask {
+    Payout,
+    MotorControl,
+    ExitSensor,
+    ExitOpto,
+    BookKeeper,
+    Sensor,
+    CcTalk,
+    Audit,
+}
+
+impl SupervisedTask {
+    const ALL: [Self; 8] = [
+        Self::Payout,
+        Self::MotorControl,
+        Self::ExitSensor,
+        Self::ExitOpto,
+        Self::BookKeeper,
+        Self::Sensor,
+        Self::CcTalk,
+        Self::Audit,
+    ];
+
+    /// Maximum time allowed between two check ins, outside of [`supervised`] waits, before the
+    /// task is considered stalled.
+    ///
+    /// The tasks driving the motor get a tighter deadline, since a stall there can leave IN3 on.
+    const fn deadline(self) -> Duration {
+        match self {
+            Self::MotorControl | Self::ExitSensor | Self::ExitOpto => Duration::from_millis(1_000),
+            Self::Payout | Self::BookKeeper | Self::Sensor | Self::CcTalk | Self::Audit => {
+                Duration::from_millis(2_000)
+            }
+        }
+    }
+}
+
+/// Cause of the last controller reset, as reported by the RCC reset flags. Reported to the host
+/// as its position in declaration order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum ResetCause {
+    /// Power-on or brown-out reset, the G0 reports both through the same flag.
+    BrownOut,
+    /// The independent or window watchdog expired.
+    Watchdog,
+    /// Software reset through `SCB::sys_reset`.
+    Software,
+    /// External reset through the NRST pin.
+    Pin,
+    /// Illegal low-power mode entry.
+    LowPower,
+    /// Option byte loading.
+    OptionByteLoad,
+    Unknown,
+}
+
+/// Longest time the supervisor sleeps while every task waits. The watchdog keeps counting in STOP
+/// mode, so this is the only wakeup it needs then.
+const FEED_INTERVAL: Duration = Duration::from_millis(2_000);
+/// Hardware watchdog timeout, it has to be longer than the longest task deadline and than
+/// [`FEED_INTERVAL`].
+const WATCHDOG_TIMEOUT_US: u32 = 4_000_000;
+
+/// Check in time of a task waiting in [`supervised`], it has no deadline meanwhile.
+const WAITING: u64 = u64::MAX;
+
+/// Every task starts out waiting, it is only supervised from its first check in on, so the
+/// storage and configuration loading done before the tasks are spawned cannot eat its deadline.
+static CHECK_INS: [AtomicU64; SupervisedTask::ALL.len()] =
+    [const { AtomicU64::new(WAITING) }; SupervisedTask::ALL.len()];
+
+/// Wakes the supervisor when a task stops waiting, so its deadline is watched.
+static TASK_RUNNING: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+static RESET_CAUSE: Mutex<CriticalSectionRawMutex, Cell<ResetCause>> =
+    Mutex::new(Cell::new(ResetCause::Unknown));
+
+/// Records that `task` is alive.
+fn check_in(task: SupervisedTask) {
+    CHECK_INS[task as usize].store(Instant::now().as_ticks(), Ordering::Relaxed);
+}
+
+/// Awaits `future`, an event `task` waits for, then checks in for it.
+///
+/// This is meant to wrap the waits of a supervised task (signals, edges, uart reads), which can
+/// last for any time. The deadline only runs while the task works between two waits, so it is
+/// reported as stalled when it stops getting back to them.
+pub async fn supervised<F: Future>(task: SupervisedTask, future: F) -> F::Output {
+    let _wait = Wait::start(task);
+    future.await
+}
+
+/// A [`supervised`] wait in progress, the task checks in when it ends or is dropped.
+struct Wait(SupervisedTask);
+
+impl Wait {
+    fn start(task: SupervisedTask) -> Self {
+        CHECK_INS[task as usize].store(WAITING, Ordering::Relaxed);
+        Self(task)
+    }
+}
+
+impl Drop for Wait {
+    fn drop(&mut self) {
+        check_in(self.0);
+        TASK_RUNNING.signal(());
+    }
+}
+
+/// Returns the cause of the last controller reset.
+pub fn last_reset_cause() -> ResetCause {
+    RESET_CAUSE.lock(Cell::get)
+}
+
+/// Reads and clears the RCC reset flags.
+///
+/// The pin reset flag is set for every reset source since NRST is driven internally, so it is
+/// checked last.
+fn read_reset_cause() -> ResetCause {
+    let csr = pac::RCC.csr().read();
+    let cause = if csr.iwdgrstf() || csr.wwdgrstf() {
+        ResetCause::Watchdog
+    } else if csr.sftrstf() {
+        ResetCause::Software
+    } else if csr.lpwrrstf() {
+        ResetCause::LowPower
+    } else if csr.oblrstf() {
+        ResetCause::OptionByteLoad
+    } else if csr.pwrrstf() {
+        ResetCause::BrownOut
+    } else if csr.pinrstf() {
+        ResetCause::Pin
+    } else {
+        ResetCause::Unknown
+    };
+    pac::RCC.csr().modify(|w| w.set_rmvf(true));
+    cause
+}
+
+/// Records the last reset cause and starts the watchdog supervisor.
+///
+/// # Panics
+///
+/// If the supervisor task fails to spawn, since the watchdog would never be fed.
+pub fn init_watchdog(spawner: Spawner, iwdg: Peri<'static, IWDG>) {
+    let cause = read_reset_cause();
+    RESET_CAUSE.lock(|c| c.set(cause));
+    if cause == ResetCause::Watchdog {
+        warn!("controller was reset by the watchdog");
+        log_event(Event::WatchdogReset);
+    } else {
+        info!("last reset cause: {}", cause);
+    }
+
+    let mut watchdog = IndependentWatchdog::new(iwdg, WATCHDOG_TIMEOUT_US);
+    watchdog.unleash();
+    spawner
+        .spawn(supervisor_task(watchdog))
+        .expect("supervisor task should run");
+}
+
+/// Feeds the independent watchdog as long as every [`SupervisedTask`] is waiting or has checked
+/// in within its deadline. Once a task misses it, the watchdog is left to expire and resets the
+/// controller.
+///
+/// The supervisor only wakes up for the deadline of a running task, or after [`FEED_INTERVAL`],
+/// so it does not keep the chip out of STOP mode while the tasks wait.
+#[embassy_executor::task]
+async fn supervisor_task(mut watchdog: IndependentWatchdog<'static, IWDG>) {
+    info!("watchdog supervisor started");
+    loop {
+        let now = Instant::now();
+        let mut healthy = true;
+        let mut next_check = now + FEED_INTERVAL;
+        for task in SupervisedTask::ALL {
+            let last = CHECK_INS[task as usize].load(Ordering::Relaxed);
+            if last == WAITING {
+                continue;
+            }
+            let deadline = Instant::from_ticks(last) + task.deadline();
+            if now > deadline {
+                error!("{} missed its watchdog deadline", task);
+                healthy = false;
+            } else {
+                next_check = next_check.min(deadline);
+            }
+        }
+
+        if healthy {
+            watchdog.pet();
+        }
+
+        select(Timer::at(next_check), TASK_RUNNING.wait()).await;
+    }
+}
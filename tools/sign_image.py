@@ -0,0 +1,99 @@
+#!/usr/bin/env python3
+"""Signs an application image for the bootloader built with `signed-images`.
+
+The image is the raw binary of the application built for the G0B1 with the `signed-images`
+feature, which the `signed-image` cargo alias writes to `target/image.bin`:
+
+    cargo signed-image
+    tools/sign_image.py sign --key key.pem target/image.bin target/image.signed.bin
+
+It starts with a 256-byte placeholder, which is replaced by the header the bootloader checks:
+
+    [ "UHAI" ] [ body length LE ] [ SHA-512 of the body ] [ Ed25519 signature of the digest ]
+
+padded with 0xFF. The bootloader is built with the public key printed by `public-key` in
+`HOPPER_PUBLIC_KEY`.
+"""
+
+import argparse
+import hashlib
+import struct
+import sys
+from pathlib import Path
+
+from cryptography.hazmat.primitives import serialization
+from cryptography.hazmat.primitives.asymmetric.ed25519 import Ed25519PrivateKey
+
+HEADER_SIZE = 256
+MAGIC = b"UHAI"
+
+
+def load_key(path: Path) -> Ed25519PrivateKey:
+    key = serialization.load_pem_private_key(path.read_bytes(), password=None)
+    if not isinstance(key, Ed25519PrivateKey):
+        sys.exit(f"{path} is not an Ed25519 key")
+    return key
+
+
+def public_key_hex(key: Ed25519PrivateKey) -> str:
+    return key.public_key().public_bytes(
+        serialization.Encoding.Raw, serialization.PublicFormat.Raw
+    ).hex()
+
+
+def generate(args: argparse.Namespace) -> None:
+    if args.key.exists():
+        sys.exit(f"{args.key} already exists")
+    key = Ed25519PrivateKey.generate()
+    args.key.write_bytes(
+        key.private_bytes(
+            serialization.Encoding.PEM,
+            serialization.PrivateFormat.PKCS8,
+            serialization.NoEncryption(),
+        )
+    )
+    print(public_key_hex(key))
+
+
+def public_key(args: argparse.Namespace) -> None:
+    print(public_key_hex(load_key(args.key)))
+
+
+def sign(args: argparse.Namespace) -> None:
+    key = load_key(args.key)
+    image = args.input.read_bytes()
+    if len(image) <= HEADER_SIZE or image[:HEADER_SIZE] != b"\xff" * HEADER_SIZE:
+        sys.exit(f"{args.input} does not start with the header placeholder, is it built with "
+                 "the `signed-images` feature?")
+
+    body = image[HEADER_SIZE:]
+    digest = hashlib.sha512(body).digest()
+    header = MAGIC + struct.pack("<I", len(body)) + digest + key.sign(digest)
+    args.output.write_bytes(header.ljust(HEADER_SIZE, b"\xff") + body)
+    print(f"image hash {digest[:4].hex()}")
+
+
+def main() -> None:
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    commands = parser.add_subparsers(required=True)
+
+    command = commands.add_parser("generate", help="create a signing key")
+    command.add_argument("key", type=Path)
+    command.set_defaults(run=generate)
+
+    command = commands.add_parser("public-key", help="print the key for HOPPER_PUBLIC_KEY")
+    command.add_argument("key", type=Path)
+    command.set_defaults(run=public_key)
+
+    command = commands.add_parser("sign", help="sign an image")
+    command.add_argument("--key", type=Path, required=True)
+    command.add_argument("input", type=Path)
+    command.add_argument("output", type=Path)
+    command.set_defaults(run=sign)
+
+    args = parser.parse_args()
+    args.run(args)
+
+
+if __name__ == "__main__":
+    main()